@@ -0,0 +1,647 @@
+//! Async wrappers for use under tokio, enabled with the `tokio` feature.
+//!
+//! Every libcephfs call blocks, and some of them wait on the MDS for a long
+//! time.  Rather than use tokio's shared blocking pool, each AsyncCephMount
+//! runs its calls on a fixed number of threads of its own, so a slow MDS
+//! can only tie up the threads of the mount talking to it.  Only a fixed
+//! number of calls are queued for those threads, any more wait in their
+//! futures until there is room.
+extern crate ceph_rust;
+use self::ceph_rust::ceph::RadosError;
+use cephfs::{self, to_io_error, CephFile, CephMount};
+use cephfs_sys::*;
+
+use futures::Stream;
+use libc::{dirent, mode_t, stat};
+use std::collections::VecDeque;
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::pin::Pin;
+use std::ptr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use tokio::sync::{oneshot, AcquireError, OwnedSemaphorePermit, Semaphore};
+
+// Entries fetched from the MDS by each read_dir task
+const DIR_BATCH: usize = 128;
+
+type Job = Box<dyn FnOnce() + Send>;
+type Call<T> = Box<dyn FnOnce() -> Result<T, RadosError> + Send>;
+type Acquire = Pin<Box<dyn Future<Output = Result<OwnedSemaphorePermit, AcquireError>> + Send>>;
+
+/// A fixed set of threads running jobs in the order they are queued.  Calls
+/// take one of a fixed number of slots while queued and running, jobs that
+/// clean up after dropped handles don't need one.
+struct BlockingPool {
+    sender: Mutex<mpsc::Sender<Job>>,
+    slots: Arc<Semaphore>,
+}
+
+impl BlockingPool {
+    fn new(threads: usize, queue_depth: usize) -> BlockingPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("cephfs-blocking-{}", i))
+                .spawn(move || loop {
+                    let job = match receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => return,
+                    };
+                    match job {
+                        Ok(job) => job(),
+                        // The pool has been dropped and the queue is empty
+                        Err(_) => return,
+                    }
+                })
+                .expect("failed to spawn cephfs blocking thread");
+        }
+        BlockingPool {
+            sender: Mutex::new(sender),
+            slots: Arc::new(Semaphore::new(threads.max(1) + queue_depth)),
+        }
+    }
+
+    fn execute(&self, job: Job) {
+        // Workers only exit once the sender is gone, so this can't fail
+        let _ = self.sender.lock().map(|sender| sender.send(job));
+    }
+
+    // Queue a call holding slot, which is given back once the call is done
+    fn submit<T>(&self,
+                 slot: OwnedSemaphorePermit,
+                 call: Call<T>)
+                 -> oneshot::Receiver<Result<T, RadosError>>
+        where T: Send + 'static
+    {
+        let (sender, receiver) = oneshot::channel();
+        self.execute(Box::new(move || {
+            let _ = sender.send(call());
+            drop(slot);
+        }));
+        receiver
+    }
+}
+
+/// The result of a call running on a mount's thread pool.  If the pool's
+/// queue is full the call is queued when there is room, as this is polled.
+pub struct Blocking<T> {
+    state: State<T>,
+}
+
+enum State<T> {
+    // Waiting for a slot in the pool's queue
+    Waiting(Arc<Shared>, Acquire, Option<Call<T>>),
+    Running(oneshot::Receiver<Result<T, RadosError>>),
+}
+
+fn pool_error(what: &str) -> RadosError {
+    RadosError::new(format!("cephfs blocking pool {}", what))
+}
+
+// Blocking is only ever used through &mut, so a &Blocking can't reach the
+// call inside it
+unsafe impl<T: Send> Sync for Blocking<T> {}
+
+impl<T: Send + 'static> Future for Blocking<T> {
+    type Output = Result<T, RadosError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, RadosError>> {
+        let this = &mut *self;
+        loop {
+            let receiver = match this.state {
+                State::Running(ref mut receiver) => {
+                    return match Pin::new(receiver).poll(cx) {
+                        Poll::Ready(Ok(result)) => Poll::Ready(result),
+                        Poll::Ready(Err(_)) => Poll::Ready(Err(pool_error("task panicked"))),
+                        Poll::Pending => Poll::Pending,
+                    };
+                }
+                State::Waiting(ref shared, ref mut acquire, ref mut call) => {
+                    let slot = match acquire.as_mut().poll(cx) {
+                        Poll::Ready(Ok(slot)) => slot,
+                        Poll::Ready(Err(_)) => return Poll::Ready(Err(pool_error("shut down"))),
+                        Poll::Pending => return Poll::Pending,
+                    };
+                    shared.pool.submit(slot, call.take().expect("call already queued"))
+                }
+            };
+            this.state = State::Running(receiver);
+        }
+    }
+}
+
+// Jobs hold a reference to this, so the mount outlives every call queued
+// on it.  When the last reference goes the mount is handed to the pool to
+// be unmounted, then the pool is dropped, letting the workers exit once the
+// queue drains.
+struct Shared {
+    pool: BlockingPool,
+    cmount: *mut ceph_mount_info,
+    mount: Option<CephMount>,
+}
+
+// libcephfs mount handles are safe to use from several threads at once
+unsafe impl Send for Shared {}
+unsafe impl Sync for Shared {}
+
+// A mount on its way to being unmounted
+struct Unmounting {
+    _mount: CephMount,
+}
+
+unsafe impl Send for Unmounting {}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        if let Some(mount) = self.mount.take() {
+            let unmounting = Unmounting { _mount: mount };
+            self.pool.execute(Box::new(move || drop(unmounting)));
+        }
+    }
+}
+
+impl Shared {
+    fn handle(&self) -> &mut ceph_mount_info {
+        unsafe { &mut *self.cmount }
+    }
+
+    fn mount(&self) -> &CephMount {
+        self.mount.as_ref().expect("mount already dropped")
+    }
+
+    fn spawn<F, T>(this: &Arc<Shared>, f: F) -> Blocking<T>
+        where F: FnOnce(&Arc<Shared>) -> Result<T, RadosError> + Send + 'static,
+              T: Send + 'static
+    {
+        let shared = this.clone();
+        let call: Call<T> = Box::new(move || f(&shared));
+        let state = match this.pool.slots.clone().try_acquire_owned() {
+            Ok(slot) => State::Running(this.pool.submit(slot, call)),
+            Err(_) => {
+                State::Waiting(this.clone(),
+                               Box::pin(this.pool.slots.clone().acquire_owned()),
+                               Some(call))
+            }
+        };
+        Blocking { state: state }
+    }
+}
+
+/// A CephMount whose calls run on a dedicated thread pool.  Clones share
+/// the mount and the pool.  The mount is unmounted once every clone, file
+/// and directory stream from it has been dropped.
+#[derive(Clone)]
+pub struct AsyncCephMount {
+    shared: Arc<Shared>,
+}
+
+impl AsyncCephMount {
+    /// Take over mount, running its calls on `threads` threads with up to
+    /// `queue_depth` more calls queued for them.
+    pub fn new(mut mount: CephMount, threads: usize, queue_depth: usize) -> AsyncCephMount {
+        let cmount = mount.handle() as *mut ceph_mount_info;
+        AsyncCephMount {
+            shared: Arc::new(Shared {
+                pool: BlockingPool::new(threads, queue_depth),
+                cmount: cmount,
+                mount: Some(mount),
+            }),
+        }
+    }
+
+    /// Run any blocking call against the mount handle on the pool.
+    pub fn run<F, T>(&self, f: F) -> Blocking<T>
+        where F: FnOnce(&mut ceph_mount_info) -> Result<T, RadosError> + Send + 'static,
+              T: Send + 'static
+    {
+        Shared::spawn(&self.shared, move |shared| f(shared.handle()))
+    }
+
+    pub fn open(&self, path: &str, flags: i32, mode: mode_t) -> Blocking<AsyncCephFile> {
+        let path = path.to_string();
+        Shared::spawn(&self.shared, move |shared| {
+            let file = try!(CephFile::open(shared.mount(), &path, flags, mode));
+            Ok(AsyncCephFile::new(shared.clone(), file))
+        })
+    }
+
+    pub fn stat(&self, path: &str) -> Blocking<stat> {
+        let path = path.to_string();
+        self.run(move |cmount| {
+            let mut stbuf: stat = unsafe { mem::zeroed() };
+            try!(cephfs::stat(cmount, &path, &mut stbuf));
+            Ok(stbuf)
+        })
+    }
+
+    pub fn mkdir(&self, path: &str, mode: mode_t) -> Blocking<()> {
+        let path = path.to_string();
+        self.run(move |cmount| cephfs::mkdir(cmount, &path, mode))
+    }
+
+    pub fn rmdir(&self, path: &str) -> Blocking<()> {
+        let path = path.to_string();
+        self.run(move |cmount| cephfs::rmdir(cmount, &path))
+    }
+
+    pub fn unlink(&self, path: &str) -> Blocking<()> {
+        let path = path.to_string();
+        self.run(move |cmount| cephfs::unlink(cmount, &path))
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> Blocking<()> {
+        let (from, to) = (from.to_string(), to.to_string());
+        self.run(move |cmount| cephfs::rename(cmount, &from, &to))
+    }
+
+    pub fn truncate(&self, path: &str, size: u64) -> Blocking<()> {
+        let path = path.to_string();
+        self.run(move |cmount| cephfs::truncate(cmount, &path, size))
+    }
+
+    /// Open a directory as a Stream of its entries, not including . and ..
+    pub fn read_dir(&self, path: &str) -> Blocking<AsyncReadDir> {
+        let path = path.to_string();
+        Shared::spawn(&self.shared, move |shared| {
+            let c_path = try!(CString::new(path));
+            let mut dirp: *mut ceph_dir_result = ptr::null_mut();
+            unsafe {
+                let ret_code = ceph_opendir(shared.handle(), c_path.as_ptr(), &mut dirp);
+                if ret_code < 0 {
+                    return Err(RadosError::new(try!(cephfs::get_error(ret_code))));
+                }
+            }
+            Ok(AsyncReadDir {
+                dir: Arc::new(OpenDir {
+                    dirp: dirp,
+                    shared: shared.clone(),
+                }),
+                pending: None,
+                entries: VecDeque::new(),
+                done: false,
+            })
+        })
+    }
+}
+
+// Closes the file on the pool when the last reference goes away
+struct OpenFile {
+    file: Option<CephFile>,
+    shared: Arc<Shared>,
+}
+
+unsafe impl Send for OpenFile {}
+unsafe impl Sync for OpenFile {}
+
+impl OpenFile {
+    fn file(&self) -> &CephFile {
+        self.file.as_ref().expect("file already closed")
+    }
+}
+
+// A file on its way to being closed.  It keeps the mount alive until then.
+struct Closing {
+    _file: CephFile,
+    _shared: Arc<Shared>,
+}
+
+unsafe impl Send for Closing {}
+
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            let closing = Closing {
+                _file: file,
+                _shared: self.shared.clone(),
+            };
+            self.shared.pool.execute(Box::new(move || drop(closing)));
+        }
+    }
+}
+
+/// An open file whose reads, writes and seeks run on the mount's pool.
+/// Reads and writes happen at the handle's own position, so clones of the
+/// same AsyncCephMount can open the file independently.
+pub struct AsyncCephFile {
+    inner: Arc<OpenFile>,
+    pos: u64,
+    read: Option<Blocking<Vec<u8>>>,
+    write: Option<Blocking<usize>>,
+    // An in progress SeekFrom::End, waiting for the file size
+    seek: Option<(Blocking<stat>, i64)>,
+}
+
+impl AsyncCephFile {
+    fn new(shared: Arc<Shared>, file: CephFile) -> AsyncCephFile {
+        AsyncCephFile {
+            inner: Arc::new(OpenFile {
+                file: Some(file),
+                shared: shared,
+            }),
+            pos: 0,
+            read: None,
+            write: None,
+            seek: None,
+        }
+    }
+
+    fn spawn<F, T>(&self, f: F) -> Blocking<T>
+        where F: FnOnce(&CephFile) -> Result<T, RadosError> + Send + 'static,
+              T: Send + 'static
+    {
+        let inner = self.inner.clone();
+        Shared::spawn(&self.inner.shared, move |_| f(inner.file()))
+    }
+
+    /// Read up to len bytes from offset, without moving the position.
+    pub fn read_at(&self, len: usize, offset: u64) -> Blocking<Vec<u8>> {
+        self.spawn(move |file| {
+            let mut buf = vec![0; len];
+            let read = try!(file.read_at(&mut buf, offset));
+            buf.truncate(read);
+            Ok(buf)
+        })
+    }
+
+    /// Write data at offset, without moving the position.
+    pub fn write_at(&self, data: Vec<u8>, offset: u64) -> Blocking<usize> {
+        self.spawn(move |file| file.write_at(&data, offset))
+    }
+
+    pub fn metadata(&self) -> Blocking<stat> {
+        self.spawn(|file| file.metadata())
+    }
+
+    pub fn set_len(&self, size: u64) -> Blocking<()> {
+        self.spawn(move |file| file.set_len(size))
+    }
+
+    pub fn sync_all(&self) -> Blocking<()> {
+        self.spawn(|file| file.sync_all())
+    }
+
+    fn poll_write_done(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let result = match self.write {
+            Some(ref mut write) => {
+                match Pin::new(write).poll(cx) {
+                    Poll::Ready(result) => result,
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            None => return Poll::Ready(Ok(())),
+        };
+        self.write = None;
+        let written = match result {
+            Err(e) => return Poll::Ready(Err(to_io_error(e))),
+            Ok(written) => written,
+        };
+        self.pos += written as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for AsyncCephFile {
+    fn poll_read(self: Pin<&mut Self>,
+                 cx: &mut Context,
+                 buf: &mut ReadBuf)
+                 -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.read.is_none() {
+            this.read = Some(this.read_at(buf.remaining(), this.pos));
+        }
+        let result = match Pin::new(this.read.as_mut().unwrap()).poll(cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.read = None;
+        let data = match result {
+            Err(e) => return Poll::Ready(Err(to_io_error(e))),
+            Ok(data) => data,
+        };
+        // The caller may have passed a smaller buffer this time round.  Any
+        // bytes that don't fit are read again next time.
+        let len = data.len().min(buf.remaining());
+        buf.put_slice(&data[..len]);
+        this.pos += len as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for AsyncCephFile {
+    fn poll_write(self: Pin<&mut Self>,
+                  cx: &mut Context,
+                  buf: &[u8])
+                  -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.write.is_none() {
+            this.write = Some(this.write_at(buf.to_vec(), this.pos));
+        }
+        let result = match Pin::new(this.write.as_mut().unwrap()).poll(cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.write = None;
+        let written = match result {
+            Err(e) => return Poll::Ready(Err(to_io_error(e))),
+            Ok(written) => written,
+        };
+        this.pos += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        // Writes go straight to libcephfs, there is nothing buffered here
+        self.get_mut().poll_write_done(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_write_done(cx)
+    }
+}
+
+impl AsyncSeek for AsyncCephFile {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        if this.write.is_some() || this.seek.is_some() {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      "another operation is pending on the file"));
+        }
+        // An abandoned read has not moved the position, so it can go
+        this.read = None;
+        let target = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => offset_by(this.pos, delta),
+            SeekFrom::End(delta) => {
+                this.seek = Some((this.metadata(), delta));
+                return Ok(());
+            }
+        };
+        match target {
+            Some(target) => {
+                this.pos = target;
+                Ok(())
+            }
+            None => Err(invalid_seek()),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        let (result, delta) = match this.seek {
+            Some((ref mut size, delta)) => {
+                match Pin::new(size).poll(cx) {
+                    Poll::Ready(result) => (result, delta),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            None => return Poll::Ready(Ok(this.pos)),
+        };
+        this.seek = None;
+        let size = match result {
+            Err(e) => return Poll::Ready(Err(to_io_error(e))),
+            Ok(stbuf) => stbuf.st_size as u64,
+        };
+        match offset_by(size, delta) {
+            Some(target) => {
+                this.pos = target;
+                Poll::Ready(Ok(target))
+            }
+            None => Poll::Ready(Err(invalid_seek())),
+        }
+    }
+}
+
+fn offset_by(base: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        base.checked_add(delta as u64)
+    } else {
+        base.checked_sub(delta.wrapping_neg() as u64)
+    }
+}
+
+fn invalid_seek() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput,
+                   "invalid seek to a negative or overflowing position")
+}
+
+/// A directory entry from AsyncReadDir.
+pub struct AsyncDirEntry {
+    pub name: OsString,
+    pub attr: stat,
+}
+
+// Closed when the stream and any batch being read have both let go of it
+struct OpenDir {
+    dirp: *mut ceph_dir_result,
+    shared: Arc<Shared>,
+}
+
+unsafe impl Send for OpenDir {}
+unsafe impl Sync for OpenDir {}
+
+// A directory on its way to being closed
+struct ClosingDir {
+    dirp: *mut ceph_dir_result,
+    shared: Arc<Shared>,
+}
+
+unsafe impl Send for ClosingDir {}
+
+impl Drop for OpenDir {
+    fn drop(&mut self) {
+        let closing = ClosingDir {
+            dirp: self.dirp,
+            shared: self.shared.clone(),
+        };
+        self.shared.pool.execute(Box::new(move || unsafe {
+            ceph_closedir(closing.shared.handle(), closing.dirp);
+        }));
+    }
+}
+
+/// A Stream of the entries of an open directory, read in batches on the
+/// mount's pool.
+pub struct AsyncReadDir {
+    dir: Arc<OpenDir>,
+    pending: Option<Blocking<(Vec<AsyncDirEntry>, bool)>>,
+    entries: VecDeque<AsyncDirEntry>,
+    done: bool,
+}
+
+// Read up to DIR_BATCH entries.  The flag is set at the end of the directory.
+fn read_batch(dir: &OpenDir) -> Result<(Vec<AsyncDirEntry>, bool), RadosError> {
+    let mut entries = Vec::with_capacity(DIR_BATCH);
+    while entries.len() < DIR_BATCH {
+        let (name, attr) = unsafe {
+            let mut de: dirent = mem::zeroed();
+            let mut attr: stat = mem::zeroed();
+            let mut stmask: i32 = 0;
+            let ret_code = ceph_readdirplus_r(dir.shared.handle(),
+                                              dir.dirp,
+                                              &mut de,
+                                              &mut attr,
+                                              &mut stmask);
+            if ret_code == 0 {
+                return Ok((entries, true));
+            }
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(cephfs::get_error(ret_code))));
+            }
+            let name = OsStr::from_bytes(CStr::from_ptr(de.d_name.as_ptr()).to_bytes())
+                .to_os_string();
+            (name, attr)
+        };
+        if name != OsStr::new(".") && name != OsStr::new("..") {
+            entries.push(AsyncDirEntry {
+                name: name,
+                attr: attr,
+            });
+        }
+    }
+    Ok((entries, false))
+}
+
+impl Stream for AsyncReadDir {
+    type Item = Result<AsyncDirEntry, RadosError>;
+
+    fn poll_next(self: Pin<&mut Self>,
+                 cx: &mut Context)
+                 -> Poll<Option<Result<AsyncDirEntry, RadosError>>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(entry) = this.entries.pop_front() {
+                return Poll::Ready(Some(Ok(entry)));
+            }
+            if this.done {
+                return Poll::Ready(None);
+            }
+            if this.pending.is_none() {
+                let dir = this.dir.clone();
+                this.pending = Some(Shared::spawn(&this.dir.shared, move |_| read_batch(&dir)));
+            }
+            let result = match Pin::new(this.pending.as_mut().unwrap()).poll(cx) {
+                Poll::Ready(result) => result,
+                Poll::Pending => return Poll::Pending,
+            };
+            this.pending = None;
+            match result {
+                Ok((entries, done)) => {
+                    this.entries.extend(entries);
+                    this.done = done;
+                }
+                Err(e) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+    }
+}
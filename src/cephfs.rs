@@ -1,10 +1,28 @@
 extern crate ceph_rust;
 use self::ceph_rust::ceph::RadosError;
 use cephfs_sys::*;
-
-use libc::{c_void, c_char, dev_t, ERANGE, mode_t, statvfs, stat, strerror, utimbuf};
-use std::ffi::{CStr, CString};
+use serde_json;
+use serde_json::Value;
+
+use libc::{c_void, c_char, dev_t, AF_INET, AF_INET6, EAGAIN, ENAMETOOLONG, ENOTCONN, EOPNOTSUPP,
+           ENOENT, ERANGE, EWOULDBLOCK, F_RDLCK, F_UNLCK, F_WRLCK, FALLOC_FL_KEEP_SIZE,
+           FALLOC_FL_PUNCH_HOLE, LOCK_EX, LOCK_NB, LOCK_SH, LOCK_UN, mode_t, SEEK_CUR, SEEK_END,
+           SEEK_SET, sockaddr_in, sockaddr_in6, sockaddr_storage, statvfs, stat, strerror,
+           utimbuf};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fmt::Display;
+use std::io;
 use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::ops::{Deref, DerefMut, Range};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+use std::process;
+use std::ptr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 struct CephFSVersion {
     major: i32,
@@ -12,7 +30,532 @@ struct CephFSVersion {
     patch: i32,
 }
 
-fn get_error(n: i32) -> Result<String, RadosError> {
+/// The uid and gid that a low level (ceph_ll_*) call is performed as.  The
+/// permission checks are done against these instead of the process
+/// credentials.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: i32,
+    pub gid: i32,
+}
+
+impl Credentials {
+    pub fn new(uid: i32, gid: i32) -> Credentials {
+        Credentials {
+            uid: uid,
+            gid: gid,
+        }
+    }
+}
+
+/// A reference to an Inode in the client's inode cache.  The reference is
+/// given back with ceph_ll_put when this is dropped.  It keeps the mount it
+/// was looked up on alive until then.
+pub struct InodeRef {
+    cmount: *mut ceph_mount_info,
+    inode: *mut Inode,
+    mount: Arc<RawMount>,
+}
+
+impl InodeRef {
+    pub fn as_ptr(&self) -> *mut Inode {
+        self.inode
+    }
+
+    // Give up ownership of the reference without ceph_ll_put
+    fn into_raw(self) -> *mut Inode {
+        let inode = self.inode;
+        unsafe {
+            drop(ptr::read(&self.mount));
+        }
+        mem::forget(self);
+        inode
+    }
+
+    /// Direct access to the objects backing this file, bypassing the
+    /// client's file cache.
+    pub fn block_io(&self) -> Result<BlockIo, RadosError> {
+        let mut layout = ceph_file_layout::default();
+        unsafe {
+            let ret_code = ceph_ll_file_layout(self.cmount, self.inode, &mut layout);
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+        }
+        Ok(BlockIo {
+            inode: self,
+            layout: layout,
+        })
+    }
+
+    /// Open this file inode with the given open flags.
+    pub fn open(&self, flags: i32, creds: Credentials) -> Result<FhHandle, RadosError> {
+        unsafe {
+            let mut fh: *mut Fh = ptr::null_mut();
+            let ret_code =
+                ceph_ll_open(self.cmount, self.inode, flags, &mut fh, creds.uid, creds.gid);
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+            Ok(FhHandle {
+                cmount: self.cmount,
+                fh: fh,
+                _mount: self.mount.clone(),
+                #[cfg(feature = "nonblocking-io")]
+                inflight: Default::default(),
+            })
+        }
+    }
+
+    /// Open this directory inode and iterate over its entries along with
+    /// their attributes.
+    pub fn read_dir(&self, creds: Credentials) -> Result<ReadDir, RadosError> {
+        unsafe {
+            let mut dirp: *mut ceph_dir_result = ptr::null_mut();
+            let ret_code = ceph_ll_opendir(self.cmount, self.inode, &mut dirp, creds.uid, creds.gid);
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+            Ok(ReadDir {
+                parent: self,
+                dirp: dirp,
+                creds: creds,
+                lookup: false,
+            })
+        }
+    }
+}
+
+impl Drop for InodeRef {
+    fn drop(&mut self) {
+        unsafe {
+            ceph_ll_put(self.cmount, self.inode);
+        }
+    }
+}
+
+/// A file opened with ceph_ll_open.  It is closed when dropped, and keeps
+/// the mount it was opened on alive until then.
+pub struct FhHandle {
+    pub(crate) cmount: *mut ceph_mount_info,
+    pub(crate) fh: *mut Fh,
+    _mount: Arc<RawMount>,
+    #[cfg(feature = "nonblocking-io")]
+    pub(crate) inflight: ::std::sync::Arc<::nonblocking::InFlight>,
+}
+
+impl FhHandle {
+    pub fn as_ptr(&self) -> *mut Fh {
+        self.fh
+    }
+
+    /// Read into buf from offset.  Returns the number of bytes read, 0 at
+    /// the end of the file.
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, RadosError> {
+        if offset > i64::max_value() as u64 {
+            return Err(RadosError::new(format!("Offset {} is too large", offset)));
+        }
+        unsafe {
+            let ret_code = ceph_ll_read(self.cmount,
+                                        self.fh,
+                                        offset as i64,
+                                        buf.len() as u64,
+                                        buf.as_mut_ptr() as *mut c_char);
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+            Ok(ret_code as usize)
+        }
+    }
+
+    pub fn write(&self, offset: u64, data: &[u8]) -> Result<usize, RadosError> {
+        if offset > i64::max_value() as u64 {
+            return Err(RadosError::new(format!("Offset {} is too large", offset)));
+        }
+        unsafe {
+            let ret_code = ceph_ll_write(self.cmount,
+                                         self.fh,
+                                         offset as i64,
+                                         data.len() as u64,
+                                         data.as_ptr() as *const c_char);
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+            Ok(ret_code as usize)
+        }
+    }
+
+    pub fn fsync(&self, data_only: bool) -> Result<(), RadosError> {
+        unsafe {
+            let ret_code = ceph_ll_fsync(self.cmount, self.fh, data_only as i32);
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FhHandle {
+    fn drop(&mut self) {
+        // Nonblocking IO still in flight refers to the handle
+        #[cfg(feature = "nonblocking-io")]
+        self.inflight.wait_idle();
+        unsafe {
+            ceph_ll_close(self.cmount, self.fh);
+        }
+    }
+}
+
+/// A directory entry returned by ceph_readdirplus_r.
+pub struct DirEntry {
+    pub name: OsString,
+    pub attr: stat,
+    /// Only filled in when the ReadDir was asked to look up its children.
+    /// Never set for . and ..
+    pub inode: Option<InodeRef>,
+}
+
+/// An open directory from ceph_ll_opendir.  It is released with
+/// ceph_ll_releasedir when dropped.
+pub struct ReadDir<'a> {
+    parent: &'a InodeRef,
+    dirp: *mut ceph_dir_result,
+    creds: Credentials,
+    lookup: bool,
+}
+
+impl<'a> ReadDir<'a> {
+    /// Also look up an InodeRef for each entry.  Every lookup takes a
+    /// reference in the inode cache that is held until the InodeRef is dropped.
+    pub fn with_inodes(mut self) -> ReadDir<'a> {
+        self.lookup = true;
+        self
+    }
+}
+
+impl<'a> Iterator for ReadDir<'a> {
+    type Item = Result<DirEntry, RadosError>;
+
+    fn next(&mut self) -> Option<Result<DirEntry, RadosError>> {
+        let (name, attr) = unsafe {
+            let mut de: dirent = mem::zeroed();
+            let mut attr: stat = mem::zeroed();
+            let mut stmask: i32 = 0;
+            let ret_code = ceph_readdirplus_r(self.parent.cmount,
+                                              self.dirp,
+                                              &mut de,
+                                              &mut attr,
+                                              &mut stmask);
+            if ret_code == 0 {
+                // End of the directory
+                return None;
+            }
+            if ret_code < 0 {
+                return Some(get_error(ret_code).and_then(|e| Err(RadosError::new(e))));
+            }
+            let name = OsStr::from_bytes(CStr::from_ptr(de.d_name.as_ptr()).to_bytes())
+                .to_os_string();
+            (name, attr)
+        };
+        let inode = if self.lookup && name != OsStr::new(".") && name != OsStr::new("..") {
+            match ll_lookup(self.parent, &name, self.creds) {
+                Ok((inode, _)) => Some(inode),
+                Err(e) => return Some(Err(e)),
+            }
+        } else {
+            None
+        };
+        Some(Ok(DirEntry {
+            name: name,
+            attr: attr,
+            inode: inode,
+        }))
+    }
+}
+
+impl<'a> Drop for ReadDir<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            ceph_ll_releasedir(self.parent.cmount, self.dirp);
+        }
+    }
+}
+
+/// Outstanding inode references held by a LookupTable.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LookupStats {
+    /// Number of distinct inodes with a non zero lookup count
+    pub inodes: usize,
+    /// Sum of the lookup counts of all inodes
+    pub outstanding: u64,
+    /// Lookups recorded since the table was created
+    pub lookups: u64,
+    /// References handed back with ceph_ll_forget since the table was created
+    pub forgets: u64,
+}
+
+struct LookupEntry {
+    inode: *mut Inode,
+    nlookup: u64,
+}
+
+/// Tracks the lookup count of every inode handed out to a FUSE style
+/// consumer so the references can be given back with ceph_ll_forget when
+/// the kernel forgets the inode.  Anything still outstanding is forgotten
+/// when the table is dropped.
+pub struct LookupTable {
+    cmount: *mut ceph_mount_info,
+    mount: Arc<RawMount>,
+    entries: HashMap<u64, LookupEntry>,
+    lookups: u64,
+    forgets: u64,
+}
+
+impl LookupTable {
+    pub fn new(mount: &CephMount) -> LookupTable {
+        LookupTable {
+            cmount: mount.cmount,
+            mount: mount.raw.clone(),
+            entries: HashMap::new(),
+            lookups: 0,
+            forgets: 0,
+        }
+    }
+
+    /// Record one lookup of inode number ino.  The reference held by the
+    /// InodeRef is moved into the table and released by a later forget.
+    pub fn remember(&mut self, ino: u64, inode: InodeRef) {
+        assert!(Arc::ptr_eq(&inode.mount, &self.mount),
+                "inode {} was looked up on a different mount",
+                ino);
+        let inode = inode.into_raw();
+        let entry = self.entries.entry(ino).or_insert(LookupEntry {
+            inode: inode,
+            nlookup: 0,
+        });
+        entry.nlookup += 1;
+        self.lookups += 1;
+    }
+
+    /// The Inode for ino if it has outstanding lookups.
+    pub fn get(&self, ino: u64) -> Option<*mut Inode> {
+        self.entries.get(&ino).map(|e| e.inode)
+    }
+
+    /// The current lookup count of ino.
+    pub fn nlookup(&self, ino: u64) -> u64 {
+        self.entries.get(&ino).map(|e| e.nlookup).unwrap_or(0)
+    }
+
+    /// Drop nlookup references to ino, as asked for by a FUSE forget.
+    pub fn forget(&mut self, ino: u64, nlookup: u64) -> Result<(), RadosError> {
+        let remaining = match self.entries.get(&ino) {
+            Some(entry) => {
+                if nlookup > entry.nlookup {
+                    return Err(RadosError::new(format!("forget of {} references to inode {} \
+                                                        which only has {}",
+                                                       nlookup,
+                                                       ino,
+                                                       entry.nlookup)));
+                }
+                try!(ll_forget(self.cmount, entry.inode, nlookup));
+                entry.nlookup - nlookup
+            }
+            None => return Err(RadosError::new(format!("inode {} has no lookups", ino))),
+        };
+        self.forgets += nlookup;
+        if remaining == 0 {
+            self.entries.remove(&ino);
+        } else if let Some(entry) = self.entries.get_mut(&ino) {
+            entry.nlookup = remaining;
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> LookupStats {
+        LookupStats {
+            inodes: self.entries.len(),
+            outstanding: self.entries.values().map(|e| e.nlookup).sum(),
+            lookups: self.lookups,
+            forgets: self.forgets,
+        }
+    }
+
+    /// The n inodes with the most outstanding lookups, largest first.
+    pub fn most_referenced(&self, n: usize) -> Vec<(u64, u64)> {
+        let mut counts: Vec<(u64, u64)> =
+            self.entries.iter().map(|(ino, e)| (*ino, e.nlookup)).collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts.truncate(n);
+        counts
+    }
+}
+
+impl Drop for LookupTable {
+    fn drop(&mut self) {
+        for (_, entry) in self.entries.drain() {
+            let _ = ll_forget(self.cmount, entry.inode, entry.nlookup);
+        }
+    }
+}
+
+fn ll_forget(cmount: *mut ceph_mount_info, inode: *mut Inode, count: u64) -> Result<(), RadosError> {
+    // ceph_ll_forget takes an int so split up very large counts
+    let mut count = count;
+    while count > 0 {
+        let batch = if count > i32::max_value() as u64 {
+            i32::max_value()
+        } else {
+            count as i32
+        };
+        unsafe {
+            let ret_code = ceph_ll_forget(cmount, inode, batch);
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+        }
+        count -= batch as u64;
+    }
+    Ok(())
+}
+
+/// Where a byte of a file lives, from BlockIo::locate.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BlockLocation {
+    /// The stripe unit sized block of the file the byte is in
+    pub blockno: u64,
+    /// The object holding that block, for read_block and write_block
+    pub object_no: u64,
+    /// Offset of the byte within the object
+    pub object_offset: u64,
+    /// The primary OSD of the object
+    pub osd: i32,
+}
+
+/// Block level I/O on the RADOS objects backing a file.  Blocks are
+/// addressed by object number, the data goes straight to the OSDs and does
+/// not go through or invalidate the file cache.
+pub struct BlockIo<'a> {
+    inode: &'a InodeRef,
+    layout: ceph_file_layout,
+}
+
+impl<'a> BlockIo<'a> {
+    pub fn layout(&self) -> ceph_file_layout {
+        self.layout
+    }
+
+    /// The sequence number of the inode's snap context, to pass to
+    /// write_block.
+    pub fn snap_seq(&self) -> u64 {
+        unsafe { ceph_ll_snap_seq(self.inode.cmount, self.inode.inode) }
+    }
+
+    /// Find the object and OSD holding offset of the file.
+    pub fn locate(&self, offset: u64) -> Result<BlockLocation, RadosError> {
+        let stripe_unit = self.layout.fl_stripe_unit as u64;
+        let stripe_count = self.layout.fl_stripe_count as u64;
+        let object_size = self.layout.fl_object_size as u64;
+        if stripe_unit == 0 || stripe_count == 0 || object_size < stripe_unit {
+            return Err(RadosError::new(format!("Inode has an invalid layout {:?}", self.layout)));
+        }
+        let blockno = offset / stripe_unit;
+        let stripes_per_object = object_size / stripe_unit;
+        let object_set = blockno / stripe_count / stripes_per_object;
+        let object_no = object_set * stripe_count + blockno % stripe_count;
+        let mut layout = self.layout;
+        unsafe {
+            let block_offset = ceph_ll_get_internal_offset(self.inode.cmount,
+                                                           self.inode.inode,
+                                                           blockno);
+            let osd = ceph_ll_get_stripe_osd(self.inode.cmount,
+                                             self.inode.inode,
+                                             blockno,
+                                             &mut layout);
+            if osd < 0 {
+                return Err(RadosError::new(try!(get_error(osd))));
+            }
+            Ok(BlockLocation {
+                blockno: blockno,
+                object_no: object_no,
+                object_offset: block_offset + offset % stripe_unit,
+                osd: osd,
+            })
+        }
+    }
+
+    /// Read from object object_no starting at offset within it.  Returns
+    /// the number of bytes read.
+    pub fn read_block(&self,
+                      object_no: u64,
+                      offset: u64,
+                      buf: &mut [u8])
+                      -> Result<usize, RadosError> {
+        let mut layout = self.layout;
+        unsafe {
+            let ret_code = ceph_ll_read_block(self.inode.cmount,
+                                              self.inode.inode,
+                                              object_no,
+                                              buf.as_mut_ptr() as *mut c_char,
+                                              offset,
+                                              buf.len() as u64,
+                                              &mut layout);
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+            Ok(ret_code as usize)
+        }
+    }
+
+    /// Write data to object object_no at offset within it.  With sync the
+    /// call waits until the write is on disk, otherwise it must be made
+    /// durable with commit_blocks.
+    pub fn write_block(&self,
+                       object_no: u64,
+                       offset: u64,
+                       data: &[u8],
+                       snapseq: u64,
+                       sync: bool)
+                       -> Result<(), RadosError> {
+        let mut layout = self.layout;
+        unsafe {
+            let ret_code = ceph_ll_write_block(self.inode.cmount,
+                                               self.inode.inode,
+                                               object_no,
+                                               data.as_ptr() as *mut c_char,
+                                               offset,
+                                               data.len() as u64,
+                                               &mut layout,
+                                               snapseq,
+                                               sync as u32);
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for unsynced block writes to the byte range of the file to
+    /// become durable.
+    pub fn commit_blocks(&self, range: Range<u64>) -> Result<(), RadosError> {
+        if range.end < range.start {
+            return Err(RadosError::new(format!("Invalid range {:?}", range)));
+        }
+        unsafe {
+            let ret_code = ceph_ll_commit_blocks(self.inode.cmount,
+                                                 self.inode.inode,
+                                                 range.start,
+                                                 range.end - range.start);
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn get_error(n: i32) -> Result<String, RadosError> {
     unsafe {
         let error_cstring = CString::from_raw(strerror(n));
         let message = try!(error_cstring.into_string());
@@ -78,37 +621,277 @@ pub fn mount(cmount: &mut ceph_mount_info, root: &str) -> Result<(), RadosError>
     Ok(())
 }
 
+// Owns the ceph_mount_info.  CephMount and everything opened through it
+// hold a reference, so the handle is only unmounted and released once the
+// last of them has been dropped.
+pub(crate) struct RawMount {
+    cmount: *mut ceph_mount_info,
+}
+
+impl Drop for RawMount {
+    fn drop(&mut self) {
+        unsafe {
+            ceph_unmount(self.cmount);
+            ceph_release(self.cmount);
+        }
+    }
+}
+
+/// A mounted ceph filesystem handle.  The filesystem is unmounted and the
+/// handle released when this and every file and inode opened through it
+/// have been dropped.
+pub struct CephMount {
+    cmount: *mut ceph_mount_info,
+    raw: Arc<RawMount>,
+    read_locality: ReadLocality,
+}
+
+impl CephMount {
+    /// The raw handle, for use with the functions in this module.
+    pub fn handle(&mut self) -> &mut ceph_mount_info {
+        unsafe { &mut *self.cmount }
+    }
+
+    pub fn as_ptr(&self) -> *mut ceph_mount_info {
+        self.cmount
+    }
+
+    /// Change which replicas reads are sent to.
+    pub fn set_read_locality(&mut self, locality: ReadLocality) -> Result<(), RadosError> {
+        try!(set_read_locality(self.handle(), locality));
+        self.read_locality = locality;
+        Ok(())
+    }
+
+    /// The read policy set with set_read_locality.
+    pub fn read_locality(&self) -> ReadLocality {
+        self.read_locality
+    }
+
+    pub fn local_osd(&mut self) -> Result<Option<i32>, RadosError> {
+        local_osd(self.handle())
+    }
+
+    /// Truncate or extend the file at path to size bytes.
+    pub fn truncate(&mut self, path: &str, size: u64) -> Result<(), RadosError> {
+        truncate(self.handle(), path, size)
+    }
+
+    /// Use layout for every file created on this mount from now on.
+    pub fn set_default_layout(&mut self, layout: &FileLayout) -> Result<(), RadosError> {
+        set_default_layout(self.handle(), layout)
+    }
+}
+
+/// Brings up a mount in the right order: create, read the config file,
+/// parse the environment, set options, init and finally mount.
+#[derive(Clone, Debug, Default)]
+pub struct MountBuilder {
+    client_id: Option<String>,
+    conf_file: Option<PathBuf>,
+    keyring: Option<PathBuf>,
+    env: Option<String>,
+    options: Vec<(String, String)>,
+    client_config: Option<ClientConfig>,
+    root: Option<String>,
+}
+
+impl MountBuilder {
+    pub fn new() -> MountBuilder {
+        MountBuilder::default()
+    }
+
+    /// The client id to connect as, without the "client." prefix.
+    pub fn client_id(mut self, id: &str) -> MountBuilder {
+        self.client_id = Some(id.to_string());
+        self
+    }
+
+    /// The config file to read.  The default search path is used if this
+    /// is not set.
+    pub fn conf_file<P: Into<PathBuf>>(mut self, path: P) -> MountBuilder {
+        self.conf_file = Some(path.into());
+        self
+    }
+
+    pub fn keyring<P: Into<PathBuf>>(mut self, path: P) -> MountBuilder {
+        self.keyring = Some(path.into());
+        self
+    }
+
+    /// An environment variable to parse as ceph command line options.
+    pub fn env(mut self, var: &str) -> MountBuilder {
+        self.env = Some(var.to_string());
+        self
+    }
+
+    /// Set a config option.  Options are applied in the order given, after
+    /// the config file and environment.
+    pub fn option<V: Display>(mut self, key: &str, value: V) -> MountBuilder {
+        self.options.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn client_config(mut self, config: ClientConfig) -> MountBuilder {
+        self.client_config = Some(config);
+        self
+    }
+
+    /// The directory of the filesystem to mount as the root.  Defaults to /
+    pub fn root(mut self, root: &str) -> MountBuilder {
+        self.root = Some(root.to_string());
+        self
+    }
+
+    pub fn mount(self) -> Result<CephMount, RadosError> {
+        let id = match self.client_id {
+            Some(ref id) => Some(try!(CString::new(id.as_str()))),
+            None => None,
+        };
+        let mut cmount: *mut ceph_mount_info = ptr::null_mut();
+        unsafe {
+            let ret_code = ceph_create(&mut cmount,
+                                       id.as_ref().map(|id| id.as_ptr()).unwrap_or(ptr::null()));
+            if ret_code < 0 {
+                return Err(mount_step_error("create", RadosError::new(try!(get_error(ret_code)))));
+            }
+        }
+        // From here on the handle is released if any step fails
+        let mut mount = CephMount {
+            cmount: cmount,
+            raw: Arc::new(RawMount { cmount: cmount }),
+            read_locality: ReadLocality::Primary,
+        };
+        try!(self.setup(mount.handle()));
+        Ok(mount)
+    }
+
+    fn setup(&self, cmount: &mut ceph_mount_info) -> Result<(), RadosError> {
+        unsafe {
+            let ret_code = match self.conf_file {
+                Some(ref path) => {
+                    let path = try!(CString::new(path.as_os_str().as_bytes()));
+                    ceph_conf_read_file(cmount, path.as_ptr())
+                }
+                None => ceph_conf_read_file(cmount, ptr::null()),
+            };
+            if ret_code < 0 {
+                return Err(mount_step_error("conf_read_file",
+                                            RadosError::new(try!(get_error(ret_code)))));
+            }
+        }
+        if let Some(ref var) = self.env {
+            try!(conf_parse_env(cmount, var).map_err(|e| mount_step_error("conf_parse_env", e)));
+        }
+        if let Some(ref keyring) = self.keyring {
+            try!(conf_set(cmount, "keyring", &keyring.to_string_lossy())
+                .map_err(|e| mount_step_error("conf_set keyring", e)));
+        }
+        for &(ref key, ref value) in &self.options {
+            try!(conf_set(cmount, key, value)
+                .map_err(|e| mount_step_error(&format!("conf_set {}", key), e)));
+        }
+        if let Some(ref config) = self.client_config {
+            try!(config.apply(cmount).map_err(|e| mount_step_error("client config", e)));
+        }
+        try!(init(cmount).map_err(|e| mount_step_error("init", e)));
+        try!(mount(cmount, self.root.as_ref().map(|r| r.as_str()).unwrap_or("/"))
+            .map_err(|e| mount_step_error("mount", e)));
+        Ok(())
+    }
+}
+
+fn mount_step_error(step: &str, err: RadosError) -> RadosError {
+    RadosError::new(format!("Mount failed at {}: {}", step, err))
+}
+
+/// Which MDS daemon(s) an mds_command is sent to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MdsSpec {
+    Rank(u32),
+    Name(String),
+    Gid(u64),
+    /// Every active MDS
+    All,
+}
+
+impl MdsSpec {
+    fn to_spec_string(&self) -> String {
+        match *self {
+            MdsSpec::Rank(rank) => rank.to_string(),
+            MdsSpec::Name(ref name) => name.clone(),
+            MdsSpec::Gid(gid) => gid.to_string(),
+            MdsSpec::All => "*".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MdsCommandOutput {
+    /// The command output, usually JSON
+    pub data: Vec<u8>,
+    /// The status string returned by the MDS
+    pub status: String,
+}
+
+impl MdsCommandOutput {
+    /// Decode the output data as JSON.
+    pub fn json(&self) -> Result<Value, RadosError> {
+        serde_json::from_slice(&self.data)
+            .map_err(|e| RadosError::new(format!("Unable to decode mds output: {}", e)))
+    }
+}
+
+/// Copy a buffer allocated by libcephfs and free it.
+unsafe fn take_ceph_buffer(buf: *mut c_char, len: usize) -> Vec<u8> {
+    if buf.is_null() {
+        return Vec::new();
+    }
+    let data = ::std::slice::from_raw_parts(buf as *const u8, len).to_vec();
+    ceph_buffer_free(buf);
+    data
+}
+
+/// Send a command to an MDS.  cmd is the JSON command object, for example
+/// {"prefix": "session ls"}.  Must have called init or mount first.
 pub fn mds_command(cmount: &mut ceph_mount_info,
-                   mds_spec: &str,
-                   cmd: &str,
-                   cmdlen: usize,
-                   inbuf: &str,
-                   inbuflen: usize,
-                   outbuf: &mut &str,
-                   outbuflen: &mut usize,
-                   outs: &mut &str,
-                   outslen: &mut usize)
-                   -> Result<(), RadosError> {
-    let mds_spec = try!(CString::new(mds_spec));
-    let cmd = try!(CString::new(cmd));
-    let inbuf = try!(CString::new(inbuf));
-    let outbuf = try!(CString::new(outbuf));
+                   target: MdsSpec,
+                   cmd: Value,
+                   input: &[u8])
+                   -> Result<MdsCommandOutput, RadosError> {
+    let mds_spec = try!(CString::new(target.to_spec_string()));
+    let cmd = try!(CString::new(cmd.to_string()));
+    let mut cmds: Vec<*const c_char> = vec![cmd.as_ptr()];
+    let mut outbuf: *mut c_char = ptr::null_mut();
+    let mut outbuflen: usize = 0;
+    let mut outs: *mut c_char = ptr::null_mut();
+    let mut outslen: usize = 0;
     unsafe {
         let ret_code = ceph_mds_command(cmount,
                                         mds_spec.as_ptr(),
-                                        &mut cmd.as_ptr(),
-                                        cmdlen,
-                                        inbuf.as_ptr(),
-                                        inbuflen,
-                                        outbuf.as_ptr(),
-                                        outbuflen,
-                                        outs,
-                                        outslen);
-        if ret_code < 0 {
-            return Err(RadosError::new(try!(get_error(ret_code))));
+                                        cmds.as_mut_ptr(),
+                                        cmds.len(),
+                                        input.as_ptr() as *const c_char,
+                                        input.len(),
+                                        &mut outbuf,
+                                        &mut outbuflen,
+                                        &mut outs,
+                                        &mut outslen);
+        // Both buffers are ours to free, even on failure
+        let data = take_ceph_buffer(outbuf, outbuflen);
+        let status = String::from_utf8_lossy(&take_ceph_buffer(outs, outslen)).into_owned();
+        if ret_code < 0 {
+            let error = try!(get_error(ret_code));
+            if status.is_empty() {
+                return Err(RadosError::new(error));
+            }
+            return Err(RadosError::new(format!("{}: {}", error, status)));
         }
+        Ok(MdsCommandOutput {
+            data: data,
+            status: status,
+        })
     }
-    Ok(())
 }
 
 pub fn unmount(cmount: &mut ceph_mount_info) -> Result<(), RadosError> {
@@ -159,13 +942,40 @@ pub fn conf_read_file(cmount: &mut ceph_mount_info, path_list: &str) -> Result<(
     Ok(())
 }
 
-pub fn conf_parse_argv(cmount: &mut ceph_mount_info,
-                       argc: i32,
-                       argv: &str)
-                       -> Result<(), RadosError> {
-    let argv = try!(CString::new(argv));
+fn argv_to_cstrings<I>(args: I) -> Result<Vec<CString>, RadosError>
+    where I: IntoIterator,
+          I::Item: AsRef<OsStr>
+{
+    let mut argv = Vec::new();
+    for arg in args {
+        argv.push(try!(CString::new(arg.as_ref().as_bytes())));
+    }
+    Ok(argv)
+}
+
+// A whole argv, which must at least have the program name
+fn program_argv<I>(args: I) -> Result<Vec<CString>, RadosError>
+    where I: IntoIterator,
+          I::Item: AsRef<OsStr>
+{
+    let argv = try!(argv_to_cstrings(args));
+    if argv.is_empty() {
+        return Err(RadosError::new("argv is empty, it needs at least the program name"
+            .to_string()));
+    }
+    Ok(argv)
+}
+
+/// Parse command line arguments into the configuration.  args is a whole
+/// argv, the first item is the program name and is skipped by ceph.
+pub fn conf_parse_argv<I>(cmount: &mut ceph_mount_info, args: I) -> Result<(), RadosError>
+    where I: IntoIterator,
+          I::Item: AsRef<OsStr>
+{
+    let args = try!(argv_to_cstrings(args));
+    let mut argv: Vec<*const c_char> = args.iter().map(|a| a.as_ptr()).collect();
     unsafe {
-        let ret_code = ceph_conf_parse_argv(cmount, argc, argv.as_ptr());
+        let ret_code = ceph_conf_parse_argv(cmount, argv.len() as i32, argv.as_mut_ptr());
         if ret_code < 0 {
             return Err(RadosError::new(try!(get_error(ret_code))));
         }
@@ -173,6 +983,36 @@ pub fn conf_parse_argv(cmount: &mut ceph_mount_info,
     Ok(())
 }
 
+/// Like conf_parse_argv but hands back the arguments ceph did not consume,
+/// with the program name still in front so they can be handed on to
+/// another argument parser.  Fails if args is empty.
+pub fn conf_parse_argv_remainder<I>(cmount: &mut ceph_mount_info,
+                                    args: I)
+                                    -> Result<Vec<OsString>, RadosError>
+    where I: IntoIterator,
+          I::Item: AsRef<OsStr>
+{
+    let args = try!(program_argv(args));
+    let mut argv: Vec<*const c_char> = args.iter().map(|a| a.as_ptr()).collect();
+    // ceph NULL terminates the remainder, which may be all of argv
+    let mut remargv: Vec<*const c_char> = vec![ptr::null(); argv.len() + 1];
+    let mut remainder = vec![OsStr::from_bytes(args[0].as_bytes()).to_os_string()];
+    unsafe {
+        let ret_code = ceph_conf_parse_argv_remainder(cmount,
+                                                      argv.len() as i32,
+                                                      argv.as_mut_ptr(),
+                                                      remargv.as_mut_ptr());
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+        // The remaining entries point back into args
+        for arg in remargv.iter().take_while(|a| !a.is_null()) {
+            remainder.push(OsStr::from_bytes(CStr::from_ptr(*arg).to_bytes()).to_os_string());
+        }
+    }
+    Ok(remainder)
+}
+
 pub fn conf_parse_env(cmount: &mut ceph_mount_info, var: &str) -> Result<(), RadosError> {
     let var = try!(CString::new(var));
     unsafe {
@@ -196,16 +1036,92 @@ pub fn conf_set(cmount: &mut ceph_mount_info, option: &str, value: &str) -> Resu
     Ok(())
 }
 
-pub fn conf_get(cmount: &mut ceph_mount_info,
-                option: &str,
-                buf: &mut &str,
-                len: usize)
-                -> Result<String, RadosError> {
+pub fn conf_get(cmount: &mut ceph_mount_info, option: &str) -> Result<String, RadosError> {
     let option = try!(CString::new(option));
-    let mut buf: Vec<u8> = Vec::with_capacity(1024);
+    let mut buf_size: usize = 1024;
     unsafe {
-        let ret_code = ceph_conf_get(cmount, option.as_ptr(), buf.as_ptr() as *mut i8, len);
-        Ok(String::from_utf8_lossy(&buf).into_owned())
+        loop {
+            let mut buf: Vec<u8> = vec![0; buf_size];
+            let ret_code =
+                ceph_conf_get(cmount, option.as_ptr(), buf.as_mut_ptr() as *mut c_char, buf.len());
+            if ret_code == -ERANGE || ret_code == -ENAMETOOLONG {
+                // buf was too small
+                buf_size *= 2;
+                continue;
+            }
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+            let value = CStr::from_ptr(buf.as_ptr() as *const c_char);
+            return Ok(value.to_string_lossy().into_owned());
+        }
+    }
+}
+
+/// Get a configuration value and parse it into T.
+pub fn get_config<T>(cmount: &mut ceph_mount_info, key: &str) -> Result<T, RadosError>
+    where T: FromStr,
+          T::Err: Display
+{
+    let value = try!(conf_get(cmount, key));
+    value.trim()
+        .parse::<T>()
+        .map_err(|e| RadosError::new(format!("Unable to parse {} value {:?}: {}", key, value, e)))
+}
+
+/// Set a configuration value from anything that can be displayed.
+pub fn set_config<V: Display>(cmount: &mut ceph_mount_info,
+                              key: &str,
+                              value: V)
+                              -> Result<(), RadosError> {
+    conf_set(cmount, key, &value.to_string())
+}
+
+/// Commonly tuned client options.  Options left as None keep their
+/// current value.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClientConfig {
+    /// Number of inodes to keep in the client metadata cache
+    pub client_cache_size: Option<u64>,
+    /// Size of the client object cache in bytes
+    pub client_oc_size: Option<u64>,
+    /// Seconds to wait for the mount to complete
+    pub client_mount_timeout: Option<f64>,
+    /// Have the client check permissions itself
+    pub client_permissions: Option<bool>,
+    /// Leave permission checks to the kernel when mounted with FUSE
+    pub fuse_default_permissions: Option<bool>,
+    /// Client debug level, for example "20" or "1/5"
+    pub debug_client: Option<String>,
+}
+
+impl ClientConfig {
+    /// Set every option that has a value.  This has to be done before the
+    /// handle is mounted as most of these are only read at mount time.
+    pub fn apply(&self, cmount: &mut ceph_mount_info) -> Result<(), RadosError> {
+        if unsafe { ceph_is_mounted(cmount) } != 0 {
+            return Err(RadosError::new("ClientConfig must be applied before mounting"
+                .to_string()));
+        }
+        if let Some(size) = self.client_cache_size {
+            try!(set_config(cmount, "client_cache_size", size));
+        }
+        if let Some(size) = self.client_oc_size {
+            try!(set_config(cmount, "client_oc_size", size));
+        }
+        if let Some(timeout) = self.client_mount_timeout {
+            try!(set_config(cmount, "client_mount_timeout", timeout));
+        }
+        if let Some(permissions) = self.client_permissions {
+            try!(set_config(cmount, "client_permissions", permissions));
+        }
+        if let Some(permissions) = self.fuse_default_permissions {
+            try!(set_config(cmount, "fuse_default_permissions", permissions));
+        }
+        if let Some(ref level) = self.debug_client {
+            try!(set_config(cmount, "debug_client", level));
+        }
+        Ok(())
     }
 }
 
@@ -616,44 +1532,644 @@ pub fn open_layout(cmount: &mut ceph_mount_info,
     Ok(())
 }
 
-pub fn close(cmount: &mut ceph_mount_info, fd: i32) -> Result<(), RadosError> {
+/// Read into buf from offset, or from the file position if offset is
+/// negative.  Returns the number of bytes read, 0 at the end of the file.
+pub fn read(cmount: &mut ceph_mount_info,
+            fd: i32,
+            buf: &mut [u8],
+            offset: i64)
+            -> Result<usize, RadosError> {
     unsafe {
-        let ret_code = ceph_close(cmount, fd);
+        let ret_code = ceph_read(cmount,
+                                 fd,
+                                 buf.as_mut_ptr() as *mut c_char,
+                                 buf.len() as i64,
+                                 offset);
         if ret_code < 0 {
             return Err(RadosError::new(try!(get_error(ret_code))));
         }
+        Ok(ret_code as usize)
     }
-    Ok(())
 }
 
-pub fn fsync(cmount: &mut ceph_mount_info, fd: i32, syncdataonly: i32) -> Result<(), RadosError> {
+/// Write data at offset, or at the file position if offset is negative.
+/// Returns the number of bytes written.
+pub fn write(cmount: &mut ceph_mount_info,
+             fd: i32,
+             data: &[u8],
+             offset: i64)
+             -> Result<usize, RadosError> {
     unsafe {
-        let ret_code = ceph_fsync(cmount, fd, syncdataonly);
+        let ret_code = ceph_write(cmount,
+                                  fd,
+                                  data.as_ptr() as *const c_char,
+                                  data.len() as i64,
+                                  offset);
         if ret_code < 0 {
             return Err(RadosError::new(try!(get_error(ret_code))));
         }
+        Ok(ret_code as usize)
     }
-    Ok(())
 }
 
-pub fn fstat(cmount: &mut ceph_mount_info, fd: i32) -> Result<stat, RadosError> {
+pub fn close(cmount: &mut ceph_mount_info, fd: i32) -> Result<(), RadosError> {
     unsafe {
-        let mut stat_buff: stat = mem::zeroed();
-        let ret_code = ceph_fstat(cmount, fd, &mut stat_buff);
+        let ret_code = ceph_close(cmount, fd);
         if ret_code < 0 {
             return Err(RadosError::new(try!(get_error(ret_code))));
         }
-        Ok(stat_buff)
     }
+    Ok(())
 }
 
-pub fn getxattr(cmount: &mut ceph_mount_info,
+/// Move the file position, returning the new position.
+pub fn lseek(cmount: &mut ceph_mount_info,
+             fd: i32,
+             offset: i64,
+             whence: i32)
+             -> Result<u64, RadosError> {
+    unsafe {
+        let ret_code = ceph_lseek(cmount, fd, offset, whence);
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code as i32))));
+        }
+        Ok(ret_code as u64)
+    }
+}
+
+pub fn fsync(cmount: &mut ceph_mount_info, fd: i32, syncdataonly: i32) -> Result<(), RadosError> {
+    unsafe {
+        let ret_code = ceph_fsync(cmount, fd, syncdataonly);
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+    }
+    Ok(())
+}
+
+/// Truncate or extend the file at path to size bytes.  Extending fills
+/// the new space with zeros.
+pub fn truncate(cmount: &mut ceph_mount_info, path: &str, size: u64) -> Result<(), RadosError> {
+    if size > i64::max_value() as u64 {
+        return Err(RadosError::new(format!("Size {} is too large", size)));
+    }
+    let path = try!(CString::new(path));
+    unsafe {
+        let ret_code = ceph_truncate(cmount, path.as_ptr(), size as i64);
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+    }
+    Ok(())
+}
+
+pub fn ftruncate(cmount: &mut ceph_mount_info, fd: i32, size: u64) -> Result<(), RadosError> {
+    if size > i64::max_value() as u64 {
+        return Err(RadosError::new(format!("Size {} is too large", size)));
+    }
+    unsafe {
+        let ret_code = ceph_ftruncate(cmount, fd, size as i64);
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+    }
+    Ok(())
+}
+
+/// What ceph_fallocate does to the range.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FallocateMode {
+    /// Allocate the range, growing the file if it ends past the end
+    Default,
+    /// Allocate the range but leave the file size alone
+    KeepSize,
+    /// Deallocate the range and zero it.  The file size never changes.
+    PunchHole,
+}
+
+impl FallocateMode {
+    fn flags(&self) -> i32 {
+        match *self {
+            FallocateMode::Default => 0,
+            FallocateMode::KeepSize => FALLOC_FL_KEEP_SIZE,
+            FallocateMode::PunchHole => FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE,
+        }
+    }
+}
+
+pub fn fallocate(cmount: &mut ceph_mount_info,
+                 fd: i32,
+                 mode: FallocateMode,
+                 offset: u64,
+                 length: u64)
+                 -> Result<(), RadosError> {
+    if offset > i64::max_value() as u64 || length > i64::max_value() as u64 {
+        return Err(RadosError::new(format!("Range {}~{} is too large", offset, length)));
+    }
+    unsafe {
+        let ret_code = ceph_fallocate(cmount, fd, mode.flags(), offset as i64, length as i64);
+        if ret_code == -EOPNOTSUPP {
+            return Err(RadosError::new(format!("fallocate mode {:?} is not supported by this \
+                                                filesystem",
+                                               mode)));
+        }
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+    }
+    Ok(())
+}
+
+pub fn fstat(cmount: &mut ceph_mount_info, fd: i32) -> Result<stat, RadosError> {
+    unsafe {
+        let mut stat_buff: stat = mem::zeroed();
+        let ret_code = ceph_fstat(cmount, fd, &mut stat_buff);
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+        Ok(stat_buff)
+    }
+}
+
+/// An open file.  It is closed when dropped, and keeps the mount it was
+/// opened on alive until then.
+pub struct CephFile {
+    cmount: *mut ceph_mount_info,
+    fd: i32,
+    lock_owner: u64,
+    _mount: Arc<RawMount>,
+}
+
+// Handed out to each CephFile so flock owners are unique per handle
+static NEXT_LOCK_OWNER: AtomicUsize = AtomicUsize::new(1);
+
+fn new_lock_owner() -> u64 {
+    let handle = NEXT_LOCK_OWNER.fetch_add(1, Ordering::SeqCst) as u64;
+    (process::id() as u64) << 32 | (handle & 0xffff_ffff)
+}
+
+impl CephFile {
+    pub fn open(mount: &CephMount,
+                path: &str,
+                flags: i32,
+                mode: mode_t)
+                -> Result<CephFile, RadosError> {
+        let path = try!(CString::new(path));
+        unsafe {
+            let fd = ceph_open(mount.cmount, path.as_ptr(), flags, mode);
+            if fd < 0 {
+                return Err(RadosError::new(try!(get_error(fd))));
+            }
+            Ok(CephFile {
+                cmount: mount.cmount,
+                fd: fd,
+                lock_owner: new_lock_owner(),
+                _mount: mount.raw.clone(),
+            })
+        }
+    }
+
+    pub fn fd(&self) -> i32 {
+        self.fd
+    }
+
+    /// The owner id this handle's flock locks are taken under.
+    pub fn lock_owner(&self) -> u64 {
+        self.lock_owner
+    }
+
+    fn flock(&self, operation: i32) -> Result<(), RadosError> {
+        flock(unsafe { &mut *self.cmount }, self.fd, operation, self.lock_owner)
+    }
+
+    /// Take a shared lock on the file, waiting for any exclusive holder.
+    pub fn lock_shared(&mut self) -> Result<FlockGuard, RadosError> {
+        try!(self.flock(LOCK_SH));
+        Ok(FlockGuard { file: self })
+    }
+
+    /// Take an exclusive lock on the file, waiting for any other holders.
+    pub fn lock_exclusive(&mut self) -> Result<FlockGuard, RadosError> {
+        try!(self.flock(LOCK_EX));
+        Ok(FlockGuard { file: self })
+    }
+
+    /// Take a shared lock if nobody holds an exclusive one.
+    pub fn try_lock_shared(&mut self) -> Result<Option<FlockGuard>, RadosError> {
+        self.try_flock(LOCK_SH)
+    }
+
+    /// Take an exclusive lock if nobody else holds a lock.
+    pub fn try_lock_exclusive(&mut self) -> Result<Option<FlockGuard>, RadosError> {
+        self.try_flock(LOCK_EX)
+    }
+
+    /// Take or release a POSIX lock on a byte range.  A range ending at
+    /// u64::MAX covers the rest of the file however far it grows.  Without
+    /// wait a conflicting lock is returned instead of waiting for it.
+    pub fn lock_range(&self,
+                      range: Range<u64>,
+                      kind: LockKind,
+                      wait: bool)
+                      -> Result<Option<LockConflict>, RadosError> {
+        let fl = try!(range_to_flock(&range, kind));
+        let ret_code =
+            unsafe { ceph_setlk(self.cmount, self.fd, &fl, self.lock_owner, wait as i32) };
+        if !wait && (ret_code == -EAGAIN || ret_code == -EWOULDBLOCK) {
+            // Find out who holds it.  It may have gone away in between, in
+            // which case the caller can just try again.
+            return match try!(self.test_lock_kind(range.clone(), kind)) {
+                Some(conflict) => Ok(Some(conflict)),
+                None => Err(RadosError::new(try!(get_error(ret_code)))),
+            };
+        }
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+        Ok(None)
+    }
+
+    /// Release any POSIX locks this handle holds on the byte range.
+    pub fn unlock_range(&self, range: Range<u64>) -> Result<(), RadosError> {
+        try!(self.lock_range(range, LockKind::Unlock, false));
+        Ok(())
+    }
+
+    /// The lock that would stop this handle taking an exclusive lock on the
+    /// byte range, if there is one.
+    pub fn test_lock(&self, range: Range<u64>) -> Result<Option<LockConflict>, RadosError> {
+        self.test_lock_kind(range, LockKind::Exclusive)
+    }
+
+    fn test_lock_kind(&self,
+                      range: Range<u64>,
+                      kind: LockKind)
+                      -> Result<Option<LockConflict>, RadosError> {
+        let mut fl = try!(range_to_flock(&range, kind));
+        unsafe {
+            let ret_code = ceph_getlk(self.cmount, self.fd, &mut fl, self.lock_owner);
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+        }
+        if fl.l_type == F_UNLCK as i16 {
+            return Ok(None);
+        }
+        let start = fl.l_start as u64;
+        let end = if fl.l_len == 0 {
+            u64::max_value()
+        } else {
+            start + fl.l_len as u64
+        };
+        Ok(Some(LockConflict {
+            kind: if fl.l_type == F_RDLCK as i16 {
+                LockKind::Shared
+            } else {
+                LockKind::Exclusive
+            },
+            range: start..end,
+            pid: fl.l_pid,
+        }))
+    }
+
+    fn try_flock(&mut self, operation: i32) -> Result<Option<FlockGuard>, RadosError> {
+        let ret_code = unsafe {
+            ceph_flock(self.cmount, self.fd, operation | LOCK_NB, self.lock_owner)
+        };
+        if ret_code == -EWOULDBLOCK {
+            return Ok(None);
+        }
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+        Ok(Some(FlockGuard { file: self }))
+    }
+
+    /// The OSDs holding the object extent that contains offset.  The
+    /// returned extent runs from offset to the end of that object extent.
+    pub fn extent_osds(&self, offset: u64) -> Result<Extent, RadosError> {
+        if offset > i64::max_value() as u64 {
+            return Err(RadosError::new(format!("Offset {} is too large", offset)));
+        }
+        unsafe {
+            loop {
+                let mut length: i64 = 0;
+                // With no room for any osds we get back how many there are
+                let count = ceph_get_file_extent_osds(self.cmount,
+                                                      self.fd,
+                                                      offset as i64,
+                                                      &mut length,
+                                                      ptr::null_mut(),
+                                                      0);
+                if count < 0 {
+                    return Err(RadosError::new(try!(get_error(count))));
+                }
+                let mut osds: Vec<i32> = vec![0; count as usize];
+                let ret_code = ceph_get_file_extent_osds(self.cmount,
+                                                         self.fd,
+                                                         offset as i64,
+                                                         &mut length,
+                                                         osds.as_mut_ptr(),
+                                                         osds.len() as i32);
+                if ret_code == -ERANGE {
+                    // The acting set grew in between, ask again
+                    continue;
+                }
+                if ret_code < 0 {
+                    return Err(RadosError::new(try!(get_error(ret_code))));
+                }
+                osds.truncate(ret_code as usize);
+                return Ok(Extent {
+                    offset: offset,
+                    length: length as u64,
+                    osds: osds,
+                });
+            }
+        }
+    }
+
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize, RadosError> {
+        if offset > i64::max_value() as u64 {
+            return Err(RadosError::new(format!("Offset {} is too large", offset)));
+        }
+        read(unsafe { &mut *self.cmount }, self.fd, buf, offset as i64)
+    }
+
+    pub fn write_at(&self, data: &[u8], offset: u64) -> Result<usize, RadosError> {
+        if offset > i64::max_value() as u64 {
+            return Err(RadosError::new(format!("Offset {} is too large", offset)));
+        }
+        write(unsafe { &mut *self.cmount }, self.fd, data, offset as i64)
+    }
+
+    pub fn metadata(&self) -> Result<stat, RadosError> {
+        fstat(unsafe { &mut *self.cmount }, self.fd)
+    }
+
+    /// Flush data and metadata to the cluster.
+    pub fn sync_all(&self) -> Result<(), RadosError> {
+        fsync(unsafe { &mut *self.cmount }, self.fd, 0)
+    }
+
+    /// Truncate or extend the file to size bytes, like std::fs::File::set_len.
+    /// The file must be open for writing.
+    pub fn set_len(&self, size: u64) -> Result<(), RadosError> {
+        ftruncate(unsafe { &mut *self.cmount }, self.fd, size)
+    }
+
+    pub fn fallocate(&self, mode: FallocateMode, offset: u64, len: u64) -> Result<(), RadosError> {
+        fallocate(unsafe { &mut *self.cmount }, self.fd, mode, offset, len)
+    }
+
+    /// Allocate space for the range, growing the file if needed.
+    pub fn allocate(&self, offset: u64, len: u64) -> Result<(), RadosError> {
+        self.fallocate(FallocateMode::Default, offset, len)
+    }
+
+    /// Free the space used by the range.  It reads back as zeros afterwards.
+    pub fn punch_hole(&self, offset: u64, len: u64) -> Result<(), RadosError> {
+        self.fallocate(FallocateMode::PunchHole, offset, len)
+    }
+
+    /// The data pool the file is stored in.
+    pub fn pool_info(&self) -> Result<PoolInfo, RadosError> {
+        let cmount = unsafe { &mut *self.cmount };
+        let id = try!(get_file_pool(cmount, self.fd));
+        pool_info(cmount, id)
+    }
+
+    /// The number of replicas kept of the file's data.
+    pub fn replication(&self) -> Result<i32, RadosError> {
+        get_file_replication(unsafe { &mut *self.cmount }, self.fd)
+    }
+
+    /// Iterate over the object extents of the whole file.
+    pub fn extents(&self) -> Result<Extents, RadosError> {
+        let size = try!(fstat(unsafe { &mut *self.cmount }, self.fd)).st_size as u64;
+        Ok(Extents {
+            file: self,
+            offset: 0,
+            size: size,
+        })
+    }
+}
+
+pub(crate) fn to_io_error(err: RadosError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+// These use the file position, like std::fs::File
+impl io::Read for CephFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        read(unsafe { &mut *self.cmount }, self.fd, buf, -1).map_err(to_io_error)
+    }
+}
+
+impl io::Write for CephFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        write(unsafe { &mut *self.cmount }, self.fd, buf, -1).map_err(to_io_error)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Seek for CephFile {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let (offset, whence) = match pos {
+            io::SeekFrom::Start(offset) => {
+                if offset > i64::max_value() as u64 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "offset too large"));
+                }
+                (offset as i64, SEEK_SET)
+            }
+            io::SeekFrom::Current(offset) => (offset, SEEK_CUR),
+            io::SeekFrom::End(offset) => (offset, SEEK_END),
+        };
+        lseek(unsafe { &mut *self.cmount }, self.fd, offset, whence).map_err(to_io_error)
+    }
+}
+
+impl Drop for CephFile {
+    fn drop(&mut self) {
+        unsafe {
+            ceph_close(self.cmount, self.fd);
+        }
+    }
+}
+
+/// The type of a POSIX byte range lock.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LockKind {
+    /// A read lock, any number can be held at once
+    Shared,
+    /// A write lock, conflicts with every other lock
+    Exclusive,
+    /// Release the range
+    Unlock,
+}
+
+/// A lock held by someone else that conflicts with the one asked for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockConflict {
+    pub kind: LockKind,
+    /// The range the conflicting lock covers.  An end of u64::MAX means the
+    /// lock runs to the end of the file.
+    pub range: Range<u64>,
+    /// The pid of the process holding the lock, on whichever client that is
+    pub pid: i32,
+}
+
+fn range_to_flock(range: &Range<u64>, kind: LockKind) -> Result<::libc::flock, RadosError> {
+    if range.end < range.start || range.start > i64::max_value() as u64 {
+        return Err(RadosError::new(format!("Invalid lock range {:?}", range)));
+    }
+    let len = if range.end == u64::max_value() {
+        // Zero means to the end of the file
+        0
+    } else if range.end - range.start > i64::max_value() as u64 {
+        return Err(RadosError::new(format!("Invalid lock range {:?}", range)));
+    } else if range.end == range.start {
+        return Err(RadosError::new(format!("Empty lock range {:?}", range)));
+    } else {
+        (range.end - range.start) as i64
+    };
+    let mut fl: ::libc::flock = unsafe { mem::zeroed() };
+    fl.l_type = match kind {
+        LockKind::Shared => F_RDLCK,
+        LockKind::Exclusive => F_WRLCK,
+        LockKind::Unlock => F_UNLCK,
+    } as i16;
+    fl.l_whence = SEEK_SET as i16;
+    fl.l_start = range.start as i64;
+    fl.l_len = len;
+    Ok(fl)
+}
+
+/// A flock lock on a CephFile.  The lock is released when this is dropped.
+/// It borrows the file mutably so a handle only ever has one guard, the file
+/// can still be used through it.
+pub struct FlockGuard<'a> {
+    file: &'a mut CephFile,
+}
+
+impl<'a> Deref for FlockGuard<'a> {
+    type Target = CephFile;
+
+    fn deref(&self) -> &CephFile {
+        self.file
+    }
+}
+
+impl<'a> DerefMut for FlockGuard<'a> {
+    fn deref_mut(&mut self) -> &mut CephFile {
+        self.file
+    }
+}
+
+impl<'a> Drop for FlockGuard<'a> {
+    fn drop(&mut self) {
+        let _ = self.file.flock(LOCK_UN);
+    }
+}
+
+/// Apply or remove an advisory lock.  operation is one of LOCK_SH, LOCK_EX
+/// or LOCK_UN, optionally with LOCK_NB.
+pub fn flock(cmount: &mut ceph_mount_info,
+             fd: i32,
+             operation: i32,
+             owner: u64)
+             -> Result<(), RadosError> {
+    unsafe {
+        let ret_code = ceph_flock(cmount, fd, operation, owner);
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+    }
+    Ok(())
+}
+
+/// A range of a file and the OSDs that store it, primary first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Extent {
+    pub offset: u64,
+    pub length: u64,
+    pub osds: Vec<i32>,
+}
+
+/// Iterator over the extents of a file from CephFile::extents.
+pub struct Extents<'a> {
+    file: &'a CephFile,
+    offset: u64,
+    size: u64,
+}
+
+impl<'a> Iterator for Extents<'a> {
+    type Item = Result<Extent, RadosError>;
+
+    fn next(&mut self) -> Option<Result<Extent, RadosError>> {
+        if self.offset >= self.size {
+            return None;
+        }
+        match self.file.extent_osds(self.offset) {
+            Ok(extent) => {
+                if extent.length == 0 {
+                    // Shouldn't happen but don't spin forever if it does
+                    self.offset = self.size;
+                } else {
+                    self.offset += extent.length;
+                }
+                Some(Ok(extent))
+            }
+            Err(e) => {
+                self.offset = self.size;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn sockaddr_to_socket_addr(addr: &sockaddr_storage) -> Result<SocketAddr, RadosError> {
+    unsafe {
+        match addr.ss_family as i32 {
+            AF_INET => {
+                let addr = &*(addr as *const sockaddr_storage as *const sockaddr_in);
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+            }
+            AF_INET6 => {
+                let addr = &*(addr as *const sockaddr_storage as *const sockaddr_in6);
+                let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                Ok(SocketAddr::V6(SocketAddrV6::new(ip,
+                                                    u16::from_be(addr.sin6_port),
+                                                    addr.sin6_flowinfo,
+                                                    addr.sin6_scope_id)))
+            }
+            family => Err(RadosError::new(format!("Unknown address family {}", family))),
+        }
+    }
+}
+
+/// The address of an OSD.
+pub fn osd_addr(cmount: &mut ceph_mount_info, osd: i32) -> Result<SocketAddr, RadosError> {
+    unsafe {
+        let mut addr: sockaddr_storage = mem::zeroed();
+        let ret_code = ceph_get_osd_addr(cmount, osd, &mut addr);
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+        sockaddr_to_socket_addr(&addr)
+    }
+}
+
+pub fn getxattr(cmount: &mut ceph_mount_info,
                 path: &str,
                 name: &str)
                 -> Result<String, RadosError> {
     let path = try!(CString::new(path));
     let name = try!(CString::new(name));
-    let value_buff: Vec<u8> = Vec::with_capacity(65 * 1024);
+    let mut value_buff: Vec<u8> = Vec::with_capacity(65 * 1024);
     unsafe {
         let ret_code = ceph_getxattr(cmount,
                                      path.as_ptr(),
@@ -944,25 +2460,25 @@ pub fn get_path_object_size(cmount: &mut ceph_mount_info, path: &str) -> Result<
     Ok(())
 }
 
-pub fn get_file_pool(cmount: &mut ceph_mount_info, fh: i32) -> Result<(), RadosError> {
+pub fn get_file_pool(cmount: &mut ceph_mount_info, fh: i32) -> Result<i32, RadosError> {
     unsafe {
         let ret_code = ceph_get_file_pool(cmount, fh);
         if ret_code < 0 {
             return Err(RadosError::new(try!(get_error(ret_code))));
         }
+        Ok(ret_code)
     }
-    Ok(())
 }
 
-pub fn get_path_pool(cmount: &mut ceph_mount_info, path: &str) -> Result<(), RadosError> {
+pub fn get_path_pool(cmount: &mut ceph_mount_info, path: &str) -> Result<i32, RadosError> {
     let path = try!(CString::new(path));
     unsafe {
         let ret_code = ceph_get_path_pool(cmount, path.as_ptr());
         if ret_code < 0 {
             return Err(RadosError::new(try!(get_error(ret_code))));
         }
+        Ok(ret_code)
     }
-    Ok(())
 }
 
 pub fn get_file_pool_name(cmount: &mut ceph_mount_info,
@@ -1047,48 +2563,208 @@ pub fn get_path_layout(cmount: &mut ceph_mount_info, path: &str) -> Result<(), R
     Ok(())
 }
 
-pub fn get_path_replication(cmount: &mut ceph_mount_info, path: &str) -> Result<(), RadosError> {
+pub fn get_path_replication(cmount: &mut ceph_mount_info, path: &str) -> Result<i32, RadosError> {
     let path = try!(CString::new(path));
     unsafe {
         let ret_code = ceph_get_path_replication(cmount, path.as_ptr());
         if ret_code < 0 {
             return Err(RadosError::new(try!(get_error(ret_code))));
         }
+        Ok(ret_code)
     }
-    Ok(())
 }
 
-pub fn get_pool_replication(cmount: &mut ceph_mount_info, pool_id: i32) -> Result<(), RadosError> {
+pub fn get_pool_replication(cmount: &mut ceph_mount_info, pool_id: i32) -> Result<i32, RadosError> {
     unsafe {
         let ret_code = ceph_get_pool_replication(cmount, pool_id);
         if ret_code < 0 {
             return Err(RadosError::new(try!(get_error(ret_code))));
         }
+        Ok(ret_code)
     }
-    Ok(())
 }
 
+pub fn get_file_replication(cmount: &mut ceph_mount_info, fh: i32) -> Result<i32, RadosError> {
+    unsafe {
+        let ret_code = ceph_get_file_replication(cmount, fh);
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+        Ok(ret_code)
+    }
+}
+
+/// A data pool and its replication size.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PoolInfo {
+    pub id: i32,
+    pub name: String,
+    /// The number of replicas
+    pub size: i32,
+}
+
+pub fn pool_id(cmount: &mut ceph_mount_info, name: &str) -> Result<i32, RadosError> {
+    let name = try!(CString::new(name));
+    unsafe {
+        let ret_code = ceph_get_pool_id(cmount, name.as_ptr());
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+        Ok(ret_code)
+    }
+}
+
+fn unknown_pool(id: i32) -> RadosError {
+    match get_error(-ENOENT) {
+        Ok(message) => RadosError::new(format!("Pool {}: {}", id, message)),
+        Err(e) => e,
+    }
+}
+
+pub fn pool_name(cmount: &mut ceph_mount_info, id: i32) -> Result<String, RadosError> {
+    unsafe {
+        loop {
+            // A zero length gets us the size needed
+            let needed = ceph_get_pool_name(cmount, id, ptr::null_mut(), 0);
+            if needed < 0 {
+                return Err(RadosError::new(try!(get_error(needed))));
+            }
+            if needed == 0 {
+                // An unknown pool id has an empty name
+                return Err(unknown_pool(id));
+            }
+            let mut buf: Vec<u8> = vec![0; needed as usize + 1];
+            let ret_code =
+                ceph_get_pool_name(cmount, id, buf.as_mut_ptr() as *mut c_char, buf.len());
+            if ret_code == -ERANGE {
+                continue;
+            }
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+            let name = CStr::from_ptr(buf.as_ptr() as *const c_char);
+            let name = name.to_string_lossy().into_owned();
+            if name.is_empty() {
+                return Err(unknown_pool(id));
+            }
+            return Ok(name);
+        }
+    }
+}
+
+pub fn pool_info(cmount: &mut ceph_mount_info, id: i32) -> Result<PoolInfo, RadosError> {
+    let name = try!(pool_name(cmount, id));
+    let size = try!(get_pool_replication(cmount, id));
+    Ok(PoolInfo {
+        id: id,
+        name: name,
+        size: size,
+    })
+}
+
+/// The data pool that the file or directory at path stores its data in.
+pub fn pool_of(cmount: &mut ceph_mount_info, path: &str) -> Result<PoolInfo, RadosError> {
+    let id = try!(get_path_pool(cmount, path));
+    pool_info(cmount, id)
+}
+
+/// The number of replicas kept of the data at path.
+pub fn replication_of(cmount: &mut ceph_mount_info, path: &str) -> Result<i32, RadosError> {
+    get_path_replication(cmount, path)
+}
+
+/// The CRUSH location of an OSD as (type, name) pairs, from the host
+/// up to the root.  For example [("host", "node1"), ("rack", "r1"),
+/// ("root", "default")].
 pub fn get_osd_crush_location(cmount: &mut ceph_mount_info,
+                              osd: i32)
+                              -> Result<Vec<(String, String)>, RadosError> {
+    let mut buf: Vec<u8>;
+    unsafe {
+        loop {
+            // A zero length gets us the size needed
+            let needed = ceph_get_osd_crush_location(cmount, osd, ptr::null_mut(), 0);
+            if needed < 0 {
+                return Err(RadosError::new(try!(get_error(needed))));
+            }
+            buf = Vec::with_capacity(needed as usize);
+            let ret_code = ceph_get_osd_crush_location(cmount,
+                                                       osd,
+                                                       buf.as_mut_ptr() as *mut c_char,
+                                                       buf.capacity());
+            if ret_code == -ERANGE {
+                // The crush map changed in between
+                continue;
+            }
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+            buf.set_len(ret_code as usize);
+            break;
+        }
+    }
+    // The buffer is type\0name\0type\0name\0...
+    let mut fields = buf.split(|c| c == &0x00).map(|s| String::from_utf8_lossy(s).into_owned());
+    let mut location = Vec::new();
+    while let (Some(key), Some(value)) = (fields.next(), fields.next()) {
+        if key.is_empty() {
+            break;
+        }
+        location.push((key, value));
+    }
+    Ok(location)
+}
 
-                              osd: i32,
+/// Parse a crush_location config value such as "host=a rack=b root=default".
+fn parse_crush_location(location: &str) -> Vec<(String, String)> {
+    location.split(|c: char| c.is_whitespace() || c == ',' || c == ';')
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if !key.is_empty() && !value.is_empty() => {
+                    Some((key.to_string(), value.to_string()))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
 
-                              path: &str,
+/// How far an OSD is from this client in the CRUSH hierarchy, compared to
+/// the client's crush_location config.  1 means they share the lowest level
+/// of the OSD's location (normally the host), 2 the level above that and so
+/// on.  None if they have nothing in common or crush_location isn't set.
+pub fn local_crush_distance(cmount: &mut ceph_mount_info,
+                            osd: i32)
+                            -> Result<Option<usize>, RadosError> {
+    let client_location = parse_crush_location(&try!(conf_get(cmount, "crush_location")));
+    if client_location.is_empty() {
+        return Ok(None);
+    }
+    let osd_location = try!(get_osd_crush_location(cmount, osd));
+    for (distance, &(ref key, ref value)) in osd_location.iter().enumerate() {
+        if client_location.iter().any(|&(ref k, ref v)| k == key && v == value) {
+            return Ok(Some(distance + 1));
+        }
+    }
+    Ok(None)
+}
 
-                              len: usize)
-                              -> Result<(), RadosError> {
-    let path = try!(CString::new(path));
+pub fn get_stripe_unit_granularity(cmount: &mut ceph_mount_info) -> Result<i32, RadosError> {
     unsafe {
-        let ret_code = ceph_get_osd_crush_location(cmount, osd, path.as_ptr() as *mut i8, len);
+        let ret_code = ceph_get_stripe_unit_granularity(cmount);
         if ret_code < 0 {
             return Err(RadosError::new(try!(get_error(ret_code))));
         }
+        Ok(ret_code)
     }
-    Ok(())
 }
 
-pub fn get_stripe_unit_granularity(cmount: &mut ceph_mount_info) -> Result<(), RadosError> {
+pub fn set_default_file_stripe_unit(cmount: &mut ceph_mount_info,
+                                    stripe: i32)
+                                    -> Result<(), RadosError> {
     unsafe {
-        let ret_code = ceph_get_stripe_unit_granularity(cmount);
+        let ret_code = ceph_set_default_file_stripe_unit(cmount, stripe);
         if ret_code < 0 {
             return Err(RadosError::new(try!(get_error(ret_code))));
         }
@@ -1119,6 +2795,88 @@ pub fn set_default_preferred_pg(cmount: &mut ceph_mount_info, osd: i32) -> Resul
     Ok(())
 }
 
+pub fn set_default_object_size(cmount: &mut ceph_mount_info, size: i32) -> Result<(), RadosError> {
+    unsafe {
+        let ret_code = ceph_set_default_object_size(cmount, size);
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+    }
+    Ok(())
+}
+
+pub fn set_default_file_replication(cmount: &mut ceph_mount_info,
+                                    replication: i32)
+                                    -> Result<(), RadosError> {
+    unsafe {
+        let ret_code = ceph_set_default_file_replication(cmount, replication);
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+    }
+    Ok(())
+}
+
+/// How a file's data is striped over objects.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FileLayout {
+    /// Bytes written to one object before moving on to the next object of
+    /// the stripe.  Must be a multiple of the stripe unit granularity.
+    pub stripe_unit: u32,
+    /// Number of objects a stripe is spread over
+    pub stripe_count: u32,
+    /// Size of each object, a multiple of the stripe unit
+    pub object_size: u32,
+    /// Number of replicas, or None to leave the default alone
+    pub replication: Option<u32>,
+}
+
+impl FileLayout {
+    /// Check the layout is one the MDS will accept.  granularity is the
+    /// value of get_stripe_unit_granularity.
+    pub fn validate(&self, granularity: u32) -> Result<(), RadosError> {
+        if self.stripe_unit == 0 || self.stripe_count == 0 || self.object_size == 0 {
+            return Err(RadosError::new(format!("Layout sizes must all be non zero: {:?}", self)));
+        }
+        if granularity != 0 && self.stripe_unit % granularity != 0 {
+            return Err(RadosError::new(format!("Stripe unit {} is not a multiple of {}",
+                                               self.stripe_unit,
+                                               granularity)));
+        }
+        if self.object_size % self.stripe_unit != 0 {
+            return Err(RadosError::new(format!("Object size {} is not a multiple of the \
+                                                stripe unit {}",
+                                               self.object_size,
+                                               self.stripe_unit)));
+        }
+        if self.object_size > i32::max_value() as u32 ||
+           self.stripe_count > i32::max_value() as u32 ||
+           self.replication.map(|r| r > i32::max_value() as u32).unwrap_or(false) {
+            return Err(RadosError::new(format!("Layout sizes are too large: {:?}", self)));
+        }
+        if self.replication == Some(0) {
+            return Err(RadosError::new("Replication must be at least 1".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Validate a layout and make it the default for files created on this
+/// mount.
+pub fn set_default_layout(cmount: &mut ceph_mount_info,
+                          layout: &FileLayout)
+                          -> Result<(), RadosError> {
+    let granularity = try!(get_stripe_unit_granularity(cmount));
+    try!(layout.validate(granularity as u32));
+    try!(set_default_object_size(cmount, layout.object_size as i32));
+    try!(set_default_file_stripe_unit(cmount, layout.stripe_unit as i32));
+    try!(set_default_file_stripe_count(cmount, layout.stripe_count as i32));
+    if let Some(replication) = layout.replication {
+        try!(set_default_file_replication(cmount, replication as i32));
+    }
+    Ok(())
+}
+
 pub fn localize_reads(cmount: &mut ceph_mount_info, val: i32) -> Result<(), RadosError> {
     unsafe {
         let ret_code = ceph_localize_reads(cmount, val);
@@ -1129,6 +2887,53 @@ pub fn localize_reads(cmount: &mut ceph_mount_info, val: i32) -> Result<(), Rado
     Ok(())
 }
 
+/// Which replica of an object reads are sent to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReadLocality {
+    /// Always read from the primary OSD.  This is the default.
+    Primary,
+    /// Read from the closest replica according to the client's
+    /// crush_location
+    Local,
+    /// Spread reads randomly over all the replicas
+    Balanced,
+}
+
+/// Set which replicas reads are sent to.  Balanced reads depend on the
+/// rados_replica_read_policy option, which older releases don't have.
+pub fn set_read_locality(cmount: &mut ceph_mount_info,
+                         locality: ReadLocality)
+                         -> Result<(), RadosError> {
+    match locality {
+        ReadLocality::Primary | ReadLocality::Local => {
+            // Undo a balanced policy where the option exists
+            let _ = conf_set(cmount, "rados_replica_read_policy", "default");
+            localize_reads(cmount, (locality == ReadLocality::Local) as i32)
+        }
+        ReadLocality::Balanced => {
+            try!(conf_set(cmount, "rados_replica_read_policy", "balance").map_err(|e| {
+                RadosError::new(format!("Balanced reads are not supported by this libcephfs: {}",
+                                        e))
+            }));
+            localize_reads(cmount, 0)
+        }
+    }
+}
+
+/// The OSD running on this client's host, if any.
+pub fn local_osd(cmount: &mut ceph_mount_info) -> Result<Option<i32>, RadosError> {
+    unsafe {
+        let osd = ceph_get_local_osd(cmount);
+        if osd == -ENOTCONN {
+            return Err(RadosError::new(try!(get_error(osd))));
+        }
+        if osd < 0 {
+            return Ok(None);
+        }
+        Ok(Some(osd))
+    }
+}
+
 pub fn debug_get_fd_caps(cmount: &mut ceph_mount_info, fd: i32) -> Result<(), RadosError> {
     unsafe {
         let ret_code = ceph_debug_get_fd_caps(cmount, fd);
@@ -1170,3 +2975,222 @@ pub fn ll_num_osds(cmount: &mut ceph_mount_info) -> Result<(), RadosError> {
     }
     Ok(())
 }
+
+pub fn ll_lookup_root(mount: &CephMount) -> Result<InodeRef, RadosError> {
+    unsafe {
+        let mut inode: *mut Inode = ptr::null_mut();
+        let ret_code = ceph_ll_lookup_root(mount.cmount, &mut inode);
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+        Ok(InodeRef {
+            cmount: mount.cmount,
+            inode: inode,
+            mount: mount.raw.clone(),
+        })
+    }
+}
+
+pub fn ll_lookup(parent: &InodeRef,
+                 name: &OsStr,
+                 creds: Credentials)
+                 -> Result<(InodeRef, stat), RadosError> {
+    let name = try!(CString::new(name.as_bytes()));
+    unsafe {
+        let mut attr: stat = mem::zeroed();
+        let mut inode: *mut Inode = ptr::null_mut();
+        let ret_code = ceph_ll_lookup(parent.cmount,
+                                      parent.inode,
+                                      name.as_ptr(),
+                                      &mut attr,
+                                      &mut inode,
+                                      creds.uid,
+                                      creds.gid);
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+        Ok((InodeRef {
+            cmount: parent.cmount,
+            inode: inode,
+            mount: parent.mount.clone(),
+        },
+            attr))
+    }
+}
+
+pub fn ll_getxattr(inode: &InodeRef,
+                   name: &OsStr,
+                   creds: Credentials)
+                   -> Result<Vec<u8>, RadosError> {
+    let name = try!(CString::new(name.as_bytes()));
+    unsafe {
+        loop {
+            // Ask for the size first.  The value can change size between the
+            // two calls so start over if it no longer fits.
+            let size = ceph_ll_getxattr(inode.cmount,
+                                        inode.inode,
+                                        name.as_ptr(),
+                                        ptr::null_mut(),
+                                        0,
+                                        creds.uid,
+                                        creds.gid);
+            if size < 0 {
+                return Err(RadosError::new(try!(get_error(size))));
+            }
+            let mut value_buf: Vec<u8> = Vec::with_capacity(size as usize);
+            let ret_code = ceph_ll_getxattr(inode.cmount,
+                                            inode.inode,
+                                            name.as_ptr(),
+                                            value_buf.as_mut_ptr() as *mut c_void,
+                                            value_buf.capacity(),
+                                            creds.uid,
+                                            creds.gid);
+            if ret_code == -ERANGE {
+                continue;
+            }
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+            value_buf.set_len(ret_code as usize);
+            return Ok(value_buf);
+        }
+    }
+}
+
+pub fn ll_setxattr(inode: &InodeRef,
+                   name: &OsStr,
+                   value: &[u8],
+                   flags: i32,
+                   creds: Credentials)
+                   -> Result<(), RadosError> {
+    let name = try!(CString::new(name.as_bytes()));
+    unsafe {
+        let ret_code = ceph_ll_setxattr(inode.cmount,
+                                        inode.inode,
+                                        name.as_ptr(),
+                                        value.as_ptr() as *const c_void,
+                                        value.len(),
+                                        flags,
+                                        creds.uid,
+                                        creds.gid);
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+    }
+    Ok(())
+}
+
+pub fn ll_listxattr(inode: &InodeRef, creds: Credentials) -> Result<Vec<OsString>, RadosError> {
+    let mut list_size: usize = 0;
+    let mut value_buf: Vec<u8>;
+    unsafe {
+        loop {
+            // A zero sized buffer makes ceph fill in list_size with the
+            // length of the whole list.
+            let ret_code = ceph_ll_listxattr(inode.cmount,
+                                             inode.inode,
+                                             ptr::null_mut(),
+                                             0,
+                                             &mut list_size,
+                                             creds.uid,
+                                             creds.gid);
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+            value_buf = Vec::with_capacity(list_size);
+            let ret_code = ceph_ll_listxattr(inode.cmount,
+                                             inode.inode,
+                                             value_buf.as_mut_ptr() as *mut c_char,
+                                             value_buf.capacity(),
+                                             &mut list_size,
+                                             creds.uid,
+                                             creds.gid);
+            if ret_code == -ERANGE {
+                // An xattr was added in between, size it again
+                continue;
+            }
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+            value_buf.set_len(list_size);
+            break;
+        }
+    }
+    let xattrs = value_buf.split(|c| c == &0x00)
+        .filter(|s| !s.is_empty())
+        .map(|s| OsString::from_vec(s.to_vec()))
+        .collect();
+    Ok(xattrs)
+}
+
+pub fn ll_removexattr(inode: &InodeRef, name: &OsStr, creds: Credentials) -> Result<(), RadosError> {
+    let name = try!(CString::new(name.as_bytes()));
+    unsafe {
+        let ret_code =
+            ceph_ll_removexattr(inode.cmount, inode.inode, name.as_ptr(), creds.uid, creds.gid);
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+    }
+    Ok(())
+}
+
+pub fn ll_readlink(inode: &InodeRef, creds: Credentials) -> Result<PathBuf, RadosError> {
+    let mut buf_size: usize = 4096;
+    unsafe {
+        loop {
+            let mut buf: Vec<u8> = Vec::with_capacity(buf_size);
+            let ret_code = ceph_ll_readlink(inode.cmount,
+                                            inode.inode,
+                                            buf.as_mut_ptr() as *mut c_char,
+                                            buf.capacity(),
+                                            creds.uid,
+                                            creds.gid);
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+            // The target is silently truncated to the buffer size so a full
+            // buffer means we may not have all of it.  Double and try again
+            if ret_code as usize == buf.capacity() {
+                buf_size *= 2;
+                continue;
+            }
+            buf.set_len(ret_code as usize);
+            return Ok(PathBuf::from(OsString::from_vec(buf)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn program_argv_needs_program_name() {
+        assert!(program_argv(Vec::<&str>::new()).is_err());
+        let argv = program_argv(vec!["prog", "--id", "admin"]).unwrap();
+        assert_eq!(argv.len(), 3);
+        assert_eq!(argv[0].to_bytes(), b"prog");
+    }
+
+    #[test]
+    fn validate_layout() {
+        let layout = FileLayout {
+            stripe_unit: 65536,
+            stripe_count: 4,
+            object_size: 4194304,
+            replication: Some(3),
+        };
+        assert!(layout.validate(65536).is_ok());
+        assert!(FileLayout { replication: None, ..layout }.validate(65536).is_ok());
+        assert!(FileLayout { stripe_count: 0, ..layout }.validate(65536).is_err());
+        assert!(FileLayout { stripe_unit: 4096, ..layout }.validate(65536).is_err());
+        assert!(FileLayout { object_size: 100000, ..layout }.validate(0).is_err());
+        assert!(FileLayout { stripe_count: 1 << 31, ..layout }.validate(65536).is_err());
+        assert!(FileLayout { replication: Some(0), ..layout }.validate(65536).is_err());
+        assert!(FileLayout { replication: Some(1 << 31), ..layout }.validate(65536).is_err());
+        assert!(FileLayout { replication: Some(u32::max_value()), ..layout }
+            .validate(65536)
+            .is_err());
+    }
+}
@@ -1,7 +1,7 @@
 // automatically generated by rust-bindgen
 #![allow(non_camel_case_types)]
-use libc::{dev_t, iovec, int64_t, mode_t, size_t, sockaddr_storage, stat, statvfs, off_t, utimbuf,
-           uint32_t, uint64_t};
+use libc::{dev_t, flock, iovec, int64_t, mode_t, size_t, sockaddr_storage, stat, statvfs, off_t,
+           utimbuf, uint32_t, uint64_t};
 
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
@@ -70,7 +70,7 @@ pub enum ceph_mount_info { }
 pub enum ceph_dir_result { }
 pub enum CephContext { }
 pub type rados_t = *mut ::std::os::raw::c_void;
-pub enum dirent { }
+pub use libc::dirent;
 
 #[link(name = "cephfs")]
 extern "C" {
@@ -157,6 +157,19 @@ extern "C" {
                                 argc: ::std::os::raw::c_int,
                                 argv: *mut *const ::std::os::raw::c_char)
                                 -> ::std::os::raw::c_int;
+    /// Parse the command line arguments and load the configuration parameters,
+    /// returning any remaining parameters.
+    /// @param cmount the mount handle to load the configuration parameters into.
+    /// @param argc count of the arguments in argv
+    /// @param argv the argument list
+    /// @param remargv an array of unused arguments, at least argc long.  Entries past the
+    /// remaining arguments are set to NULL.
+    /// @returns 0 on success, negative error code on failure
+    pub fn ceph_conf_parse_argv_remainder(cmount: *mut ceph_mount_info,
+                                          argc: ::std::os::raw::c_int,
+                                          argv: *mut *const ::std::os::raw::c_char,
+                                          remargv: *mut *const ::std::os::raw::c_char)
+                                          -> ::std::os::raw::c_int;
     /// Configure the cluster handle based on an environment variable
     /// The contents of the environment variable are parsed as if they were
     /// Ceph command line options. If var is NULL, the CEPH_ARGS
@@ -437,6 +450,31 @@ extern "C" {
                       operation: ::std::os::raw::c_int,
                       owner: uint64_t)
                       -> ::std::os::raw::c_int;
+    /// Get the file lock.
+    /// @param cmount the ceph mount handle to use for performing the lock.
+    /// @param fd the open file descriptor to get the file lock from.
+    /// @param fl the file lock to test.  On return l_type is F_UNLCK if nothing conflicts,
+    /// otherwise it describes the conflicting lock.
+    /// @param owner the user-supplied owner identifier (an arbitrary integer)
+    /// @returns 0 on success or negative error code on failure.
+    pub fn ceph_getlk(cmount: *mut ceph_mount_info,
+                      fd: ::std::os::raw::c_int,
+                      fl: *mut flock,
+                      owner: uint64_t)
+                      -> ::std::os::raw::c_int;
+    /// Set or release a POSIX file lock.
+    /// @param cmount the ceph mount handle to use for performing the lock.
+    /// @param fd the open file descriptor to set the file lock on.
+    /// @param fl the file lock to set: F_RDLCK, F_WRLCK or F_UNLCK over a byte range.
+    /// @param owner the user-supplied owner identifier (an arbitrary integer)
+    /// @param sleep non-zero to wait for a conflicting lock to be released
+    /// @returns 0 on success or negative error code on failure.
+    pub fn ceph_setlk(cmount: *mut ceph_mount_info,
+                      fd: ::std::os::raw::c_int,
+                      fl: *const flock,
+                      owner: uint64_t,
+                      sleep: ::std::os::raw::c_int)
+                      -> ::std::os::raw::c_int;
     /// Truncate the file to the given size.  If this operation causes the
     /// file to expand, the empty bytes will be filled in with zeros.
     pub fn ceph_truncate(cmount: *mut ceph_mount_info,
@@ -1011,7 +1049,7 @@ extern "C" {
     pub fn ceph_ll_file_layout(cmount: *mut ceph_mount_info,
                                in_: *mut Inode,
                                layout: *mut ceph_file_layout)
-                               -> uint32_t;
+                               -> ::std::os::raw::c_int;
     pub fn ceph_ll_snap_seq(cmount: *mut ceph_mount_info, in_: *mut Inode) -> uint64_t;
     pub fn ceph_ll_get_stripe_osd(cmount: *mut ceph_mount_info,
                                   in_: *mut Inode,
@@ -1051,3 +1089,35 @@ extern "C" {
                                  range: uint64_t)
                                  -> ::std::os::raw::c_int;
 }
+
+/// Describes an IO for ceph_ll_nonblocking_readv_writev.  It and the iovecs
+/// it points to must stay valid until callback has been called.
+#[cfg(feature = "nonblocking-io")]
+#[repr(C)]
+pub struct ceph_ll_io_info {
+    pub callback: Option<unsafe extern "C" fn(cb_info: *mut ceph_ll_io_info)>,
+    pub priv_: *mut ::std::os::raw::c_void,
+    pub fh: *mut Fh,
+    pub iov: *const iovec,
+    pub iovcnt: ::std::os::raw::c_int,
+    pub off: int64_t,
+    /// Bytes transferred or a negative error code, set before callback is called.
+    pub result: int64_t,
+    pub write: bool,
+    pub fsync: bool,
+    pub syncdataonly: bool,
+}
+
+// Only present in libcephfs 10.0.3 (Reef) and later, so it is behind the
+// nonblocking-io feature to keep linking against older releases working.
+#[cfg(feature = "nonblocking-io")]
+#[link(name = "cephfs")]
+extern "C" {
+    /// Start a read or write described by io_info without waiting for it.
+    /// io_info->callback is called once the IO completes.
+    /// @returns a negative error code if the IO could not be started, in
+    /// which case the callback is never called.
+    pub fn ceph_ll_nonblocking_readv_writev(cmount: *mut ceph_mount_info,
+                                            io_info: *mut ceph_ll_io_info)
+                                            -> int64_t;
+}
@@ -0,0 +1,673 @@
+//! A lease based mutex shared by every client of the filesystem, for jobs
+//! that must only run in one place at a time.
+//!
+//! The lock is an exclusive flock on a lock file.  The holder and the time
+//! its lease runs out are kept in xattrs on the file.  If a holder hangs
+//! without letting go, once its lease has run out the lock file is moved
+//! aside, unlinked and replaced, leaving the stale holder with a lock on a
+//! file nobody else can see.  Holders can check they still have the lock
+//! with still_held.
+extern crate ceph_rust;
+use self::ceph_rust::ceph::RadosError;
+use cephfs::{fstat, get_error, link, rename, stat, unlink, CephFile, CephMount};
+use cephfs_sys::*;
+
+use libc::{c_void, gethostname, ENODATA, O_CREAT, O_RDWR};
+use std::ffi::CString;
+use std::mem;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const HOLDER_XATTR: &'static str = "user.lock.holder";
+const EXPIRY_XATTR: &'static str = "user.lock.expiry";
+
+// Give up after this many lock files have been replaced under us
+const MAX_ATTEMPTS: usize = 3;
+
+/// The filesystem operations DistributedLock needs.  CephLockBackend is the
+/// real one, other implementations can stand in for it in tests.
+pub trait LockBackend {
+    type File;
+
+    /// Open path, creating it if it doesn't exist.
+    fn open(&mut self, path: &str) -> Result<Self::File, RadosError>;
+    /// Take an exclusive lock on the file without waiting.  false if
+    /// someone else holds it.
+    fn try_lock(&mut self, file: &mut Self::File) -> Result<bool, RadosError>;
+    /// Release the lock and close the file.
+    fn unlock(&mut self, file: Self::File) -> Result<(), RadosError>;
+    /// The inode number of an open file.
+    fn file_ino(&mut self, file: &Self::File) -> Result<u64, RadosError>;
+    /// The inode number at path, or None if nothing is there.
+    fn path_ino(&mut self, path: &str) -> Result<Option<u64>, RadosError>;
+    /// An xattr of the file, or None if it isn't set.
+    fn get_xattr(&mut self, file: &Self::File, name: &str) -> Result<Option<Vec<u8>>, RadosError>;
+    fn set_xattr(&mut self, file: &Self::File, name: &str, value: &[u8]) -> Result<(), RadosError>;
+    /// Remove an xattr of the file.  Succeeds if it isn't set.
+    fn remove_xattr(&mut self, file: &Self::File, name: &str) -> Result<(), RadosError>;
+    /// Rename from to to, replacing anything already at to.
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), RadosError>;
+    /// Add to as another name for from.  Fails if to already exists.
+    fn link(&mut self, from: &str, to: &str) -> Result<(), RadosError>;
+    fn remove(&mut self, path: &str) -> Result<(), RadosError>;
+}
+
+/// LockBackend over a ceph mount.
+pub struct CephLockBackend<'a> {
+    mount: &'a mut CephMount,
+}
+
+impl<'a> CephLockBackend<'a> {
+    pub fn new(mount: &'a mut CephMount) -> CephLockBackend<'a> {
+        CephLockBackend { mount: mount }
+    }
+}
+
+impl<'a> LockBackend for CephLockBackend<'a> {
+    type File = CephFile;
+
+    fn open(&mut self, path: &str) -> Result<CephFile, RadosError> {
+        CephFile::open(self.mount, path, O_CREAT | O_RDWR, 0o644)
+    }
+
+    fn try_lock(&mut self, file: &mut CephFile) -> Result<bool, RadosError> {
+        match try!(file.try_lock_exclusive()) {
+            Some(guard) => {
+                // The lock is released in unlock, or when the file is closed
+                mem::forget(guard);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn unlock(&mut self, file: CephFile) -> Result<(), RadosError> {
+        try!(::cephfs::flock(self.mount.handle(),
+                             file.fd(),
+                             ::libc::LOCK_UN,
+                             file.lock_owner()));
+        Ok(())
+    }
+
+    fn file_ino(&mut self, file: &CephFile) -> Result<u64, RadosError> {
+        Ok(try!(fstat(self.mount.handle(), file.fd())).st_ino as u64)
+    }
+
+    fn path_ino(&mut self, path: &str) -> Result<Option<u64>, RadosError> {
+        let mut st: ::libc::stat = unsafe { mem::zeroed() };
+        match stat(self.mount.handle(), path, &mut st) {
+            Ok(()) => Ok(Some(st.st_ino as u64)),
+            // Treat any failure as the file having gone, the caller will
+            // find out soon enough if something is really wrong
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn get_xattr(&mut self, file: &CephFile, name: &str) -> Result<Option<Vec<u8>>, RadosError> {
+        let name = try!(CString::new(name));
+        let mut value_buf: Vec<u8> = Vec::with_capacity(4096);
+        unsafe {
+            let ret_code = ceph_fgetxattr(self.mount.handle(),
+                                          file.fd(),
+                                          name.as_ptr(),
+                                          value_buf.as_mut_ptr() as *mut c_void,
+                                          value_buf.capacity());
+            if ret_code == -ENODATA {
+                return Ok(None);
+            }
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+            value_buf.set_len(ret_code as usize);
+        }
+        Ok(Some(value_buf))
+    }
+
+    fn set_xattr(&mut self, file: &CephFile, name: &str, value: &[u8]) -> Result<(), RadosError> {
+        ::cephfs::fsetxattr(self.mount.handle(),
+                            file.fd(),
+                            name,
+                            value.as_ptr() as *const c_void,
+                            value.len(),
+                            0)
+    }
+
+    fn remove_xattr(&mut self, file: &CephFile, name: &str) -> Result<(), RadosError> {
+        let name = try!(CString::new(name));
+        unsafe {
+            let ret_code = ceph_fremovexattr(self.mount.handle(), file.fd(), name.as_ptr());
+            if ret_code < 0 && ret_code != -ENODATA {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+        }
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), RadosError> {
+        rename(self.mount.handle(), from, to)
+    }
+
+    fn link(&mut self, from: &str, to: &str) -> Result<(), RadosError> {
+        link(self.mount.handle(), from, to)
+    }
+
+    fn remove(&mut self, path: &str) -> Result<(), RadosError> {
+        unlink(self.mount.handle(), path)
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// hostname:pid of this process.
+fn default_holder() -> String {
+    let mut buf = [0u8; 256];
+    let host = unsafe {
+        if gethostname(buf.as_mut_ptr() as *mut ::libc::c_char, buf.len()) == 0 {
+            let len = buf.iter().position(|c| *c == 0).unwrap_or(buf.len());
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        } else {
+            "unknown".to_string()
+        }
+    };
+    format!("{}:{}", host, process::id())
+}
+
+// Keeps the names stale lock files are moved aside to unique for a holder
+static NEXT_ASIDE: AtomicUsize = AtomicUsize::new(0);
+
+/// Get rid of the stale lock file with inode ino at path.  It is renamed
+/// out of the way first, which only one of several clients taking over at
+/// once can do.  If what got moved turns out to be a newer lock file it is
+/// linked back.  Should yet another have turned up at path in the meantime
+/// the newer file is left where it was moved to, and its holder finds out
+/// from still_held.
+fn take_over<B: LockBackend>(backend: &mut B,
+                             path: &str,
+                             ino: u64,
+                             holder: &str)
+                             -> Result<(), RadosError> {
+    let aside = format!("{}.stale.{}.{}",
+                        path,
+                        holder.replace('/', "_"),
+                        NEXT_ASIDE.fetch_add(1, Ordering::SeqCst));
+    if let Err(e) = backend.rename(path, &aside) {
+        if try!(backend.path_ino(path)) != Some(ino) {
+            // Someone else got there first
+            return Ok(());
+        }
+        return Err(e);
+    }
+    if try!(backend.path_ino(&aside)) == Some(ino) {
+        return backend.remove(&aside);
+    }
+    if let Err(e) = backend.link(&aside, path) {
+        return Err(RadosError::new(format!("Moved live lock file {} to {} and could not put \
+                                            it back: {}",
+                                           path,
+                                           aside,
+                                           e)));
+    }
+    // It is back at path, only the extra name goes
+    backend.remove(&aside)
+}
+
+/// A held lock.  It is released when dropped.
+pub struct DistributedLock<B: LockBackend> {
+    backend: B,
+    file: Option<B::File>,
+    path: String,
+    holder: String,
+    ttl: Duration,
+}
+
+impl<'a> DistributedLock<CephLockBackend<'a>> {
+    /// Try to take the lock at path for ttl.  None if somebody else holds
+    /// it and their lease hasn't run out.
+    pub fn acquire(mount: &'a mut CephMount,
+                   path: &str,
+                   ttl: Duration)
+                   -> Result<Option<DistributedLock<CephLockBackend<'a>>>, RadosError> {
+        DistributedLock::acquire_with(CephLockBackend::new(mount), path, ttl, &default_holder())
+    }
+}
+
+impl<B: LockBackend> DistributedLock<B> {
+    /// acquire with any backend, holding the lock under the given identity.
+    pub fn acquire_with(mut backend: B,
+                        path: &str,
+                        ttl: Duration,
+                        holder: &str)
+                        -> Result<Option<DistributedLock<B>>, RadosError> {
+        if ttl == Duration::from_secs(0) {
+            return Err(RadosError::new(format!("Lock {} needs a ttl above zero", path)));
+        }
+        for _ in 0..MAX_ATTEMPTS {
+            let mut file = try!(backend.open(path));
+            if try!(backend.try_lock(&mut file)) {
+                // A takeover may have replaced the file after we opened it, in
+                // which case our lock is on the old one
+                let ino = try!(backend.file_ino(&file));
+                if try!(backend.path_ino(path)) != Some(ino) {
+                    try!(backend.unlock(file));
+                    continue;
+                }
+                let mut lock = DistributedLock {
+                    backend: backend,
+                    file: Some(file),
+                    path: path.to_string(),
+                    holder: holder.to_string(),
+                    ttl: ttl,
+                };
+                try!(lock.write_lease());
+                return Ok(Some(lock));
+            }
+
+            // Held by someone else.  Take it over if their lease is up.  A
+            // holder that hasn't written its lease yet is never stale, the
+            // last holder's lease is cleared when it lets go.
+            let holder_set = try!(backend.get_xattr(&file, HOLDER_XATTR))
+                .map(|v| !v.is_empty())
+                .unwrap_or(false);
+            let expiry = try!(backend.get_xattr(&file, EXPIRY_XATTR))
+                .and_then(|v| String::from_utf8_lossy(&v).trim().parse::<u64>().ok());
+            match expiry {
+                Some(expiry) if holder_set && expiry < now_secs() => {
+                    let ino = try!(backend.file_ino(&file));
+                    drop(file);
+                    try!(take_over(&mut backend, path, ino, holder));
+                }
+                _ => return Ok(None),
+            }
+        }
+        Ok(None)
+    }
+
+    fn write_lease(&mut self) -> Result<(), RadosError> {
+        // Round up, so a lease never runs out before its ttl
+        let ttl = self.ttl.as_secs() + (self.ttl.subsec_nanos() > 0) as u64;
+        let expiry = now_secs() + ttl;
+        let file = self.file.as_ref().expect("lock already released");
+        try!(self.backend.set_xattr(file, HOLDER_XATTR, self.holder.as_bytes()));
+        self.backend.set_xattr(file, EXPIRY_XATTR, expiry.to_string().as_bytes())
+    }
+
+    /// Extend the lease by another ttl from now.  Long running holders must
+    /// call this before the lease runs out or the lock can be taken over.
+    /// Fails if it already has been.
+    pub fn renew(&mut self) -> Result<(), RadosError> {
+        if !try!(self.still_held()) {
+            return Err(RadosError::new(format!("Lock {} has been taken over by another holder",
+                                               self.path)));
+        }
+        self.write_lease()
+    }
+
+    /// Whether our lock file is still the one at path.  false once the lock
+    /// has been taken over.
+    pub fn still_held(&mut self) -> Result<bool, RadosError> {
+        let ino = {
+            let file = self.file.as_ref().expect("lock already released");
+            try!(self.backend.file_ino(file))
+        };
+        Ok(try!(self.backend.path_ino(&self.path)) == Some(ino))
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    // Clear the lease before unlocking, so whoever takes the lock next
+    // can't be mistaken for a stale holder before writing their own
+    fn unlock(&mut self, file: B::File) -> Result<(), RadosError> {
+        let cleared = self.backend
+            .remove_xattr(&file, EXPIRY_XATTR)
+            .and_then(|_| self.backend.remove_xattr(&file, HOLDER_XATTR));
+        let unlocked = self.backend.unlock(file);
+        cleared.and(unlocked)
+    }
+
+    /// Release the lock now, reporting any error.
+    pub fn release(mut self) -> Result<(), RadosError> {
+        match self.file.take() {
+            Some(file) => self.unlock(file),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<B: LockBackend> Drop for DistributedLock<B> {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            let _ = self.unlock(file);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    const PATH: &'static str = "/locks/job";
+
+    #[derive(Default)]
+    struct MockFs {
+        next_ino: u64,
+        next_handle: u64,
+        paths: HashMap<String, u64>,
+        // Inode to the handle holding its lock
+        locks: HashMap<u64, u64>,
+        xattrs: HashMap<(u64, String), Vec<u8>>,
+        // Replace the file at the path right after this many opens
+        replace_on_open: usize,
+        // Put a new file where one was renamed from this many times
+        replace_on_rename: usize,
+    }
+
+    impl MockFs {
+        fn create(&mut self, path: &str) -> u64 {
+            self.next_ino += 1;
+            self.paths.insert(path.to_string(), self.next_ino);
+            self.next_ino
+        }
+    }
+
+    struct MockFile {
+        ino: u64,
+        handle: u64,
+    }
+
+    struct MockBackend {
+        fs: Rc<RefCell<MockFs>>,
+    }
+
+    fn missing(path: &str) -> RadosError {
+        RadosError::new(format!("{}: No such file or directory", path))
+    }
+
+    impl LockBackend for MockBackend {
+        type File = MockFile;
+
+        fn open(&mut self, path: &str) -> Result<MockFile, RadosError> {
+            let mut fs = self.fs.borrow_mut();
+            let ino = match fs.paths.get(path).cloned() {
+                Some(ino) => ino,
+                None => fs.create(path),
+            };
+            if fs.replace_on_open > 0 {
+                fs.replace_on_open -= 1;
+                fs.create(path);
+            }
+            fs.next_handle += 1;
+            Ok(MockFile {
+                ino: ino,
+                handle: fs.next_handle,
+            })
+        }
+
+        fn try_lock(&mut self, file: &mut MockFile) -> Result<bool, RadosError> {
+            let mut fs = self.fs.borrow_mut();
+            match fs.locks.get(&file.ino) {
+                Some(handle) if *handle != file.handle => return Ok(false),
+                _ => {}
+            }
+            fs.locks.insert(file.ino, file.handle);
+            Ok(true)
+        }
+
+        fn unlock(&mut self, file: MockFile) -> Result<(), RadosError> {
+            let mut fs = self.fs.borrow_mut();
+            if fs.locks.get(&file.ino) == Some(&file.handle) {
+                fs.locks.remove(&file.ino);
+            }
+            Ok(())
+        }
+
+        fn file_ino(&mut self, file: &MockFile) -> Result<u64, RadosError> {
+            Ok(file.ino)
+        }
+
+        fn path_ino(&mut self, path: &str) -> Result<Option<u64>, RadosError> {
+            Ok(self.fs.borrow().paths.get(path).cloned())
+        }
+
+        fn get_xattr(&mut self,
+                     file: &MockFile,
+                     name: &str)
+                     -> Result<Option<Vec<u8>>, RadosError> {
+            Ok(self.fs.borrow().xattrs.get(&(file.ino, name.to_string())).cloned())
+        }
+
+        fn set_xattr(&mut self,
+                     file: &MockFile,
+                     name: &str,
+                     value: &[u8])
+                     -> Result<(), RadosError> {
+            self.fs.borrow_mut().xattrs.insert((file.ino, name.to_string()), value.to_vec());
+            Ok(())
+        }
+
+        fn remove_xattr(&mut self, file: &MockFile, name: &str) -> Result<(), RadosError> {
+            self.fs.borrow_mut().xattrs.remove(&(file.ino, name.to_string()));
+            Ok(())
+        }
+
+        fn rename(&mut self, from: &str, to: &str) -> Result<(), RadosError> {
+            let mut fs = self.fs.borrow_mut();
+            let ino = try!(fs.paths.remove(from).ok_or_else(|| missing(from)));
+            fs.paths.insert(to.to_string(), ino);
+            if fs.replace_on_rename > 0 {
+                fs.replace_on_rename -= 1;
+                fs.create(from);
+            }
+            Ok(())
+        }
+
+        fn link(&mut self, from: &str, to: &str) -> Result<(), RadosError> {
+            let mut fs = self.fs.borrow_mut();
+            if fs.paths.contains_key(to) {
+                return Err(RadosError::new(format!("{}: File exists", to)));
+            }
+            let ino = try!(fs.paths.get(from).cloned().ok_or_else(|| missing(from)));
+            fs.paths.insert(to.to_string(), ino);
+            Ok(())
+        }
+
+        fn remove(&mut self, path: &str) -> Result<(), RadosError> {
+            self.fs.borrow_mut().paths.remove(path).map(|_| ()).ok_or_else(|| missing(path))
+        }
+    }
+
+    fn new_fs() -> Rc<RefCell<MockFs>> {
+        Rc::new(RefCell::new(MockFs::default()))
+    }
+
+    fn acquire_for(fs: &Rc<RefCell<MockFs>>,
+                   holder: &str,
+                   ttl: Duration)
+                   -> Result<Option<DistributedLock<MockBackend>>, RadosError> {
+        DistributedLock::acquire_with(MockBackend { fs: fs.clone() }, PATH, ttl, holder)
+    }
+
+    fn acquire(fs: &Rc<RefCell<MockFs>>, holder: &str) -> Option<DistributedLock<MockBackend>> {
+        acquire_for(fs, holder, Duration::from_secs(60)).unwrap()
+    }
+
+    fn expiry(fs: &Rc<RefCell<MockFs>>) -> Option<u64> {
+        let ino = current_ino(fs);
+        fs.borrow()
+            .xattrs
+            .get(&(ino, EXPIRY_XATTR.to_string()))
+            .map(|v| String::from_utf8_lossy(v).parse::<u64>().unwrap())
+    }
+
+    fn current_ino(fs: &Rc<RefCell<MockFs>>) -> u64 {
+        fs.borrow().paths[PATH]
+    }
+
+    fn expire(fs: &Rc<RefCell<MockFs>>) {
+        let ino = current_ino(fs);
+        fs.borrow_mut().xattrs.insert((ino, EXPIRY_XATTR.to_string()), b"1".to_vec());
+    }
+
+    #[test]
+    fn uncontended_acquire() {
+        let fs = new_fs();
+        let mut lock = acquire(&fs, "a").expect("lock should be free");
+        assert_eq!(lock.path(), PATH);
+        assert_eq!(lock.holder(), "a");
+        assert!(lock.still_held().unwrap());
+
+        let ino = current_ino(&fs);
+        let fs = fs.borrow();
+        assert_eq!(fs.locks.len(), 1);
+        assert!(fs.locks.contains_key(&ino));
+        assert_eq!(fs.xattrs[&(ino, HOLDER_XATTR.to_string())], b"a".to_vec());
+        let expiry = String::from_utf8(fs.xattrs[&(ino, EXPIRY_XATTR.to_string())].clone())
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+        assert!(expiry >= now_secs() + 59);
+    }
+
+    #[test]
+    fn ttl_rounds_up() {
+        let fs = new_fs();
+        let _a = acquire_for(&fs, "a", Duration::from_millis(500)).unwrap().unwrap();
+        assert!(expiry(&fs).unwrap() >= now_secs() + 1);
+        assert!(acquire_for(&new_fs(), "a", Duration::from_secs(0)).is_err());
+    }
+
+    #[test]
+    fn contended_acquire() {
+        let fs = new_fs();
+        let mut a = acquire(&fs, "a").unwrap();
+        assert!(acquire(&fs, "b").is_none());
+        assert!(a.still_held().unwrap());
+        assert!(a.renew().is_ok());
+    }
+
+    #[test]
+    fn stale_takeover() {
+        let fs = new_fs();
+        let mut a = acquire(&fs, "a").unwrap();
+        let stale = current_ino(&fs);
+        expire(&fs);
+
+        let mut b = acquire(&fs, "b").expect("stale lock should be taken over");
+        assert!(current_ino(&fs) != stale);
+        assert!(b.still_held().unwrap());
+        assert!(!a.still_held().unwrap());
+        assert!(a.renew().is_err());
+        assert!(b.renew().is_ok());
+        // Nothing left behind where the stale file was moved aside
+        assert_eq!(fs.borrow().paths.len(), 1);
+    }
+
+    #[test]
+    fn takeover_of_replaced_file() {
+        let fs = new_fs();
+        let _a = acquire(&fs, "a").unwrap();
+        let stale = current_ino(&fs);
+        expire(&fs);
+        let mut b = acquire(&fs, "b").unwrap();
+        let fresh = current_ino(&fs);
+
+        // c saw the stale file too, but b beat it to the takeover
+        let mut backend = MockBackend { fs: fs.clone() };
+        take_over(&mut backend, PATH, stale, "c").unwrap();
+        assert_eq!(current_ino(&fs), fresh);
+        assert!(b.still_held().unwrap());
+        assert_eq!(fs.borrow().paths.len(), 1);
+        assert!(acquire(&fs, "c").is_none());
+    }
+
+    #[test]
+    fn replaced_after_open() {
+        let fs = new_fs();
+        fs.borrow_mut().replace_on_open = 1;
+        let mut a = acquire(&fs, "a").expect("should retry on the new file");
+        assert!(a.still_held().unwrap());
+
+        // Only the lock on the file now at the path is still held
+        let ino = current_ino(&fs);
+        let fs = fs.borrow();
+        assert_eq!(fs.locks.len(), 1);
+        assert!(fs.locks.contains_key(&ino));
+    }
+
+    #[test]
+    fn drop_unlocks() {
+        let fs = new_fs();
+        {
+            let _a = acquire(&fs, "a").unwrap();
+            assert!(acquire(&fs, "b").is_none());
+        }
+        assert!(fs.borrow().locks.is_empty());
+        assert!(acquire(&fs, "b").is_some());
+    }
+
+    #[test]
+    fn release_unlocks() {
+        let fs = new_fs();
+        let a = acquire(&fs, "a").unwrap();
+        a.release().unwrap();
+        assert!(fs.borrow().locks.is_empty());
+        assert!(fs.borrow().xattrs.is_empty());
+        assert!(acquire(&fs, "b").is_some());
+    }
+
+    // c has taken the lock a let go of but not written its lease yet, b must
+    // not mistake a's old lease for c's
+    fn contender_in_gap(let_go: &dyn Fn(DistributedLock<MockBackend>)) {
+        let fs = new_fs();
+        let a = acquire(&fs, "a").unwrap();
+        let ino = current_ino(&fs);
+        fs.borrow_mut().xattrs.insert((ino, EXPIRY_XATTR.to_string()), b"1".to_vec());
+        let_go(a);
+        assert_eq!(expiry(&fs), None);
+
+        let mut c = MockBackend { fs: fs.clone() };
+        let mut file = c.open(PATH).unwrap();
+        assert!(c.try_lock(&mut file).unwrap());
+        assert!(acquire(&fs, "b").is_none());
+        assert_eq!(current_ino(&fs), ino);
+        assert_eq!(fs.borrow().paths.len(), 1);
+    }
+
+    #[test]
+    fn release_then_relock() {
+        contender_in_gap(&|lock| lock.release().unwrap());
+    }
+
+    #[test]
+    fn drop_then_relock() {
+        contender_in_gap(&|lock| drop(lock));
+    }
+
+    #[test]
+    fn takeover_keeps_file_it_cannot_put_back() {
+        let fs = new_fs();
+        let _a = acquire(&fs, "a").unwrap();
+        let stale = current_ino(&fs);
+        expire(&fs);
+        let mut b = acquire(&fs, "b").unwrap();
+        let fresh = current_ino(&fs);
+
+        // c moves b's file aside, and another turns up before it is put back
+        fs.borrow_mut().replace_on_rename = 1;
+        let mut backend = MockBackend { fs: fs.clone() };
+        assert!(take_over(&mut backend, PATH, stale, "c").is_err());
+        assert!(fs.borrow().paths.values().any(|ino| *ino == fresh));
+        assert!(!b.still_held().unwrap());
+    }
+}
@@ -0,0 +1,1091 @@
+//! A FileSystem trait so code can be written once and run against CephFS,
+//! a directory on local disk, or an in-memory tree in tests.
+//!
+//! Paths are absolute, with / being the root of the mount, the LocalFs root
+//! directory, or the MemFs root.
+extern crate ceph_rust;
+use self::ceph_rust::ceph::RadosError;
+use cephfs::{self, get_error, CephFile, CephMount};
+use cephfs_sys::*;
+
+use libc::{c_char, c_void, dirent, mode_t, stat, utimbuf, EFBIG, ENODATA, ERANGE, O_ACCMODE,
+           O_APPEND, O_CREAT, O_EXCL, O_RDONLY, O_TRUNC, O_WRONLY, S_IFDIR, S_IFLNK, S_IFMT,
+           S_IFREG};
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// File attributes, a subset of struct stat.  Times are in seconds since
+/// the epoch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub ino: u64,
+    pub mode: u32,
+    pub nlink: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub len: u64,
+    pub atime: i64,
+    pub mtime: i64,
+    pub ctime: i64,
+}
+
+impl Metadata {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+}
+
+impl<'a> From<&'a stat> for Metadata {
+    fn from(st: &'a stat) -> Metadata {
+        Metadata {
+            ino: st.st_ino as u64,
+            mode: st.st_mode as u32,
+            nlink: st.st_nlink as u64,
+            uid: st.st_uid,
+            gid: st.st_gid,
+            len: st.st_size as u64,
+            atime: st.st_atime as i64,
+            mtime: st.st_mtime as i64,
+            ctime: st.st_ctime as i64,
+        }
+    }
+}
+
+/// An entry returned by FileSystem::read_dir.
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: OsString,
+    pub metadata: Metadata,
+}
+
+/// Attributes to change with FileSystem::set_attr.  Fields left as None are
+/// not touched.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SetAttr {
+    /// Permission bits only, the file type can't be changed
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Truncate or extend the file to this size
+    pub size: Option<u64>,
+    pub atime: Option<i64>,
+    pub mtime: Option<i64>,
+}
+
+pub trait FileSystem {
+    type File: Read + Write + Seek;
+
+    /// Open path with open(2) style flags, creating it with mode if O_CREAT
+    /// is given.
+    fn open(&self, path: &str, flags: i32, mode: mode_t) -> Result<Self::File, RadosError>;
+    /// Attributes of path, not following a final symlink.
+    fn metadata(&self, path: &str) -> Result<Metadata, RadosError>;
+    /// The entries of a directory, not including . and ..
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, RadosError>;
+    /// Create a directory and any missing parents.  Succeeds if it exists.
+    fn create_dir_all(&self, path: &str) -> Result<(), RadosError>;
+    /// Remove a file, symlink or empty directory.
+    fn remove(&self, path: &str) -> Result<(), RadosError>;
+    fn rename(&self, from: &str, to: &str) -> Result<(), RadosError>;
+    /// The value of an xattr, or None if it isn't set.
+    fn get_xattr(&self, path: &str, name: &str) -> Result<Option<Vec<u8>>, RadosError>;
+    fn set_xattr(&self, path: &str, name: &str, value: &[u8]) -> Result<(), RadosError>;
+    fn list_xattrs(&self, path: &str) -> Result<Vec<OsString>, RadosError>;
+    fn remove_xattr(&self, path: &str, name: &str) -> Result<(), RadosError>;
+    fn set_attr(&self, path: &str, attr: &SetAttr) -> Result<(), RadosError>;
+}
+
+fn join(dir: &str, name: &OsStr) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name.to_string_lossy())
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+impl FileSystem for CephMount {
+    type File = CephFile;
+
+    fn open(&self, path: &str, flags: i32, mode: mode_t) -> Result<CephFile, RadosError> {
+        CephFile::open(self, path, flags, mode)
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, RadosError> {
+        let mut st: stat = unsafe { mem::zeroed() };
+        try!(cephfs::lstat(unsafe { &mut *self.as_ptr() }, path, &mut st));
+        Ok(Metadata::from(&st))
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, RadosError> {
+        let cmount = self.as_ptr();
+        let c_path = try!(CString::new(path));
+        let mut entries = Vec::new();
+        unsafe {
+            let mut dirp: *mut ceph_dir_result = ptr::null_mut();
+            let ret_code = ceph_opendir(cmount, c_path.as_ptr(), &mut dirp);
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+            loop {
+                let mut de: dirent = mem::zeroed();
+                let mut st: stat = mem::zeroed();
+                let mut stmask: i32 = 0;
+                let ret_code = ceph_readdirplus_r(cmount, dirp, &mut de, &mut st, &mut stmask);
+                if ret_code < 0 {
+                    ceph_closedir(cmount, dirp);
+                    return Err(RadosError::new(try!(get_error(ret_code))));
+                }
+                if ret_code == 0 {
+                    break;
+                }
+                let name = OsStr::from_bytes(CStr::from_ptr(de.d_name.as_ptr()).to_bytes());
+                if name != OsStr::new(".") && name != OsStr::new("..") {
+                    entries.push(DirEntry {
+                        name: name.to_os_string(),
+                        metadata: Metadata::from(&st),
+                    });
+                }
+            }
+            ceph_closedir(cmount, dirp);
+        }
+        Ok(entries)
+    }
+
+    fn create_dir_all(&self, path: &str) -> Result<(), RadosError> {
+        match self.metadata(path) {
+            Ok(ref metadata) if metadata.is_dir() => Ok(()),
+            _ => cephfs::mkdirs(unsafe { &mut *self.as_ptr() }, path, 0o755),
+        }
+    }
+
+    fn remove(&self, path: &str) -> Result<(), RadosError> {
+        let cmount = unsafe { &mut *self.as_ptr() };
+        if try!(self.metadata(path)).is_dir() {
+            cephfs::rmdir(cmount, path)
+        } else {
+            cephfs::unlink(cmount, path)
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), RadosError> {
+        cephfs::rename(unsafe { &mut *self.as_ptr() }, from, to)
+    }
+
+    fn get_xattr(&self, path: &str, name: &str) -> Result<Option<Vec<u8>>, RadosError> {
+        let path = try!(CString::new(path));
+        let name = try!(CString::new(name));
+        unsafe {
+            // Ask for the size first so any value fits
+            let ret_code =
+                ceph_lgetxattr(self.as_ptr(), path.as_ptr(), name.as_ptr(), ptr::null_mut(), 0);
+            if ret_code == -ENODATA {
+                return Ok(None);
+            }
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+            let mut value: Vec<u8> = Vec::with_capacity(ret_code as usize);
+            let ret_code = ceph_lgetxattr(self.as_ptr(),
+                                          path.as_ptr(),
+                                          name.as_ptr(),
+                                          value.as_mut_ptr() as *mut c_void,
+                                          value.capacity());
+            if ret_code == -ENODATA {
+                return Ok(None);
+            }
+            if ret_code < 0 {
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+            value.set_len(ret_code as usize);
+            Ok(Some(value))
+        }
+    }
+
+    fn set_xattr(&self, path: &str, name: &str, value: &[u8]) -> Result<(), RadosError> {
+        cephfs::lsetxattr(unsafe { &mut *self.as_ptr() },
+                          path,
+                          name,
+                          value.as_ptr() as *const c_void,
+                          value.len(),
+                          0)
+    }
+
+    fn list_xattrs(&self, path: &str) -> Result<Vec<OsString>, RadosError> {
+        let c_path = try!(CString::new(path));
+        loop {
+            unsafe {
+                let size = ceph_llistxattr(self.as_ptr(), c_path.as_ptr(), ptr::null_mut(), 0);
+                if size < 0 {
+                    return Err(RadosError::new(try!(get_error(size))));
+                }
+                let mut list: Vec<u8> = Vec::with_capacity(size as usize);
+                let ret_code = ceph_llistxattr(self.as_ptr(),
+                                               c_path.as_ptr(),
+                                               list.as_mut_ptr() as *mut c_char,
+                                               list.capacity());
+                if ret_code == -ERANGE {
+                    // An xattr was added since asking for the size
+                    continue;
+                }
+                if ret_code < 0 {
+                    return Err(RadosError::new(try!(get_error(ret_code))));
+                }
+                list.set_len(ret_code as usize);
+                return Ok(xattr_names(&list));
+            }
+        }
+    }
+
+    fn remove_xattr(&self, path: &str, name: &str) -> Result<(), RadosError> {
+        cephfs::lremovexattr(unsafe { &mut *self.as_ptr() }, path, name)
+    }
+
+    fn set_attr(&self, path: &str, attr: &SetAttr) -> Result<(), RadosError> {
+        let cmount = unsafe { &mut *self.as_ptr() };
+        if let Some(mode) = attr.mode {
+            try!(cephfs::chmod(cmount, path, (mode & 0o7777) as mode_t));
+        }
+        if attr.uid.is_some() || attr.gid.is_some() {
+            // -1 leaves the id as it is
+            let uid = attr.uid.map(|uid| uid as i32).unwrap_or(-1);
+            let gid = attr.gid.map(|gid| gid as i32).unwrap_or(-1);
+            try!(cephfs::chown(cmount, path, uid, gid));
+        }
+        if let Some(size) = attr.size {
+            try!(cephfs::truncate(cmount, path, size));
+        }
+        if attr.atime.is_some() || attr.mtime.is_some() {
+            let current = try!(self.metadata(path));
+            let mut times = utimbuf {
+                actime: attr.atime.unwrap_or(current.atime),
+                modtime: attr.mtime.unwrap_or(current.mtime),
+            };
+            try!(cephfs::utime(cmount, path, &mut times));
+        }
+        Ok(())
+    }
+}
+
+// The names are NUL terminated, one after the other
+fn xattr_names(list: &[u8]) -> Vec<OsString> {
+    list.split(|c| *c == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| OsStr::from_bytes(name).to_os_string())
+        .collect()
+}
+
+fn io_error(path: &str, err: io::Error) -> RadosError {
+    RadosError::new(format!("{}: {}", path, err))
+}
+
+/// A FileSystem over std::fs, with every path taken relative to a root
+/// directory on local disk.  Symlinks under the root are followed wherever
+/// they point, so it is no sandbox.
+pub struct LocalFs {
+    root: PathBuf,
+}
+
+impl LocalFs {
+    pub fn new<P: Into<PathBuf>>(root: P) -> LocalFs {
+        LocalFs { root: root.into() }
+    }
+
+    // .. is resolved before joining so it can't climb above the root, a
+    // symlink can still lead out of it
+    fn local_path(&self, path: &str) -> PathBuf {
+        self.root.join(normalize(path).trim_start_matches('/'))
+    }
+
+    fn c_path(&self, path: &str) -> Result<CString, RadosError> {
+        Ok(try!(CString::new(self.local_path(path).into_os_string().into_vec())))
+    }
+}
+
+impl FileSystem for LocalFs {
+    type File = fs::File;
+
+    fn open(&self, path: &str, flags: i32, mode: mode_t) -> Result<fs::File, RadosError> {
+        let access = flags & O_ACCMODE;
+        OpenOptions::new()
+            .read(access != O_WRONLY)
+            .write(access != O_RDONLY)
+            .custom_flags(flags & !O_ACCMODE)
+            .mode(mode as u32)
+            .open(self.local_path(path))
+            .map_err(|e| io_error(path, e))
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, RadosError> {
+        let metadata = try!(fs::symlink_metadata(self.local_path(path))
+            .map_err(|e| io_error(path, e)));
+        Ok(Metadata {
+            ino: metadata.ino(),
+            mode: metadata.mode(),
+            nlink: metadata.nlink(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            len: metadata.size(),
+            atime: metadata.atime(),
+            mtime: metadata.mtime(),
+            ctime: metadata.ctime(),
+        })
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, RadosError> {
+        let mut entries = Vec::new();
+        for entry in try!(fs::read_dir(self.local_path(path)).map_err(|e| io_error(path, e))) {
+            let entry = try!(entry.map_err(|e| io_error(path, e)));
+            let name = entry.file_name();
+            let metadata = try!(self.metadata(&join(path, &name)));
+            entries.push(DirEntry {
+                name: name,
+                metadata: metadata,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn create_dir_all(&self, path: &str) -> Result<(), RadosError> {
+        fs::create_dir_all(self.local_path(path)).map_err(|e| io_error(path, e))
+    }
+
+    fn remove(&self, path: &str) -> Result<(), RadosError> {
+        let local = self.local_path(path);
+        let result = if try!(self.metadata(path)).is_dir() {
+            fs::remove_dir(local)
+        } else {
+            fs::remove_file(local)
+        };
+        result.map_err(|e| io_error(path, e))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), RadosError> {
+        fs::rename(self.local_path(from), self.local_path(to)).map_err(|e| io_error(from, e))
+    }
+
+    fn get_xattr(&self, path: &str, name: &str) -> Result<Option<Vec<u8>>, RadosError> {
+        let c_path = try!(self.c_path(path));
+        let c_name = try!(CString::new(name));
+        loop {
+            unsafe {
+                let size = ::libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), ptr::null_mut(), 0);
+                if size < 0 {
+                    let err = io::Error::last_os_error();
+                    if err.raw_os_error() == Some(ENODATA) {
+                        return Ok(None);
+                    }
+                    return Err(io_error(path, err));
+                }
+                let mut value: Vec<u8> = Vec::with_capacity(size as usize);
+                let read = ::libc::lgetxattr(c_path.as_ptr(),
+                                             c_name.as_ptr(),
+                                             value.as_mut_ptr() as *mut c_void,
+                                             value.capacity());
+                if read < 0 {
+                    let err = io::Error::last_os_error();
+                    match err.raw_os_error() {
+                        Some(ENODATA) => return Ok(None),
+                        // It grew between the two calls
+                        Some(::libc::ERANGE) => continue,
+                        _ => return Err(io_error(path, err)),
+                    }
+                }
+                value.set_len(read as usize);
+                return Ok(Some(value));
+            }
+        }
+    }
+
+    fn set_xattr(&self, path: &str, name: &str, value: &[u8]) -> Result<(), RadosError> {
+        let c_path = try!(self.c_path(path));
+        let c_name = try!(CString::new(name));
+        unsafe {
+            if ::libc::lsetxattr(c_path.as_ptr(),
+                                 c_name.as_ptr(),
+                                 value.as_ptr() as *const c_void,
+                                 value.len(),
+                                 0) < 0 {
+                return Err(io_error(path, io::Error::last_os_error()));
+            }
+        }
+        Ok(())
+    }
+
+    fn list_xattrs(&self, path: &str) -> Result<Vec<OsString>, RadosError> {
+        let c_path = try!(self.c_path(path));
+        loop {
+            unsafe {
+                let size = ::libc::llistxattr(c_path.as_ptr(), ptr::null_mut(), 0);
+                if size < 0 {
+                    return Err(io_error(path, io::Error::last_os_error()));
+                }
+                let mut list: Vec<u8> = Vec::with_capacity(size as usize);
+                let read = ::libc::llistxattr(c_path.as_ptr(),
+                                              list.as_mut_ptr() as *mut c_char,
+                                              list.capacity());
+                if read < 0 {
+                    let err = io::Error::last_os_error();
+                    if err.raw_os_error() == Some(ERANGE) {
+                        continue;
+                    }
+                    return Err(io_error(path, err));
+                }
+                list.set_len(read as usize);
+                return Ok(xattr_names(&list));
+            }
+        }
+    }
+
+    fn remove_xattr(&self, path: &str, name: &str) -> Result<(), RadosError> {
+        let c_path = try!(self.c_path(path));
+        let c_name = try!(CString::new(name));
+        unsafe {
+            if ::libc::lremovexattr(c_path.as_ptr(), c_name.as_ptr()) < 0 {
+                return Err(io_error(path, io::Error::last_os_error()));
+            }
+        }
+        Ok(())
+    }
+
+    fn set_attr(&self, path: &str, attr: &SetAttr) -> Result<(), RadosError> {
+        let local = self.local_path(path);
+        if let Some(mode) = attr.mode {
+            try!(fs::set_permissions(&local, fs::Permissions::from_mode(mode & 0o7777))
+                .map_err(|e| io_error(path, e)));
+        }
+        if attr.uid.is_some() || attr.gid.is_some() {
+            let c_path = try!(self.c_path(path));
+            // -1 leaves the id as it is
+            let uid = attr.uid.unwrap_or(!0);
+            let gid = attr.gid.unwrap_or(!0);
+            unsafe {
+                if ::libc::lchown(c_path.as_ptr(), uid, gid) < 0 {
+                    return Err(io_error(path, io::Error::last_os_error()));
+                }
+            }
+        }
+        if let Some(size) = attr.size {
+            let file = try!(OpenOptions::new()
+                .write(true)
+                .open(&local)
+                .map_err(|e| io_error(path, e)));
+            try!(file.set_len(size).map_err(|e| io_error(path, e)));
+        }
+        if attr.atime.is_some() || attr.mtime.is_some() {
+            let current = try!(self.metadata(path));
+            let times = utimbuf {
+                actime: attr.atime.unwrap_or(current.atime),
+                modtime: attr.mtime.unwrap_or(current.mtime),
+            };
+            let c_path = try!(self.c_path(path));
+            unsafe {
+                if ::libc::utime(c_path.as_ptr(), &times) < 0 {
+                    return Err(io_error(path, io::Error::last_os_error()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+enum MemData {
+    File(Arc<Mutex<Vec<u8>>>),
+    Dir,
+}
+
+struct MemNode {
+    data: MemData,
+    metadata: Metadata,
+    xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+struct MemTree {
+    nodes: BTreeMap<String, MemNode>,
+    next_ino: u64,
+}
+
+impl MemTree {
+    fn node(&self, path: &str) -> Result<&MemNode, RadosError> {
+        self.nodes.get(path).ok_or_else(|| not_found(path))
+    }
+
+    fn node_mut(&mut self, path: &str) -> Result<&mut MemNode, RadosError> {
+        self.nodes.get_mut(path).ok_or_else(|| not_found(path))
+    }
+
+    fn children<'a>(&'a self, dir: &str) -> Vec<(&'a String, &'a MemNode)> {
+        let prefix = if dir == "/" { "/".to_string() } else { format!("{}/", dir) };
+        self.nodes
+            .range(prefix.clone()..)
+            .take_while(|&(path, _)| path.starts_with(&prefix))
+            .filter(|&(path, _)| path.len() > prefix.len() && !path[prefix.len()..].contains('/'))
+            .collect()
+    }
+
+    fn insert(&mut self, path: &str, data: MemData, mode: u32) -> Result<(), RadosError> {
+        let parent = try!(self.node(parent_of(path)));
+        if !parent.metadata.is_dir() {
+            return Err(RadosError::new(format!("{}: Not a directory", parent_of(path))));
+        }
+        let now = now_secs();
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        let kind = match data {
+            MemData::File(_) => S_IFREG,
+            MemData::Dir => S_IFDIR,
+        };
+        self.nodes.insert(path.to_string(),
+                          MemNode {
+                              data: data,
+                              metadata: Metadata {
+                                  ino: ino,
+                                  mode: kind | (mode & 0o7777),
+                                  nlink: 1,
+                                  uid: 0,
+                                  gid: 0,
+                                  len: 0,
+                                  atime: now,
+                                  mtime: now,
+                                  ctime: now,
+                              },
+                              xattrs: BTreeMap::new(),
+                          });
+        Ok(())
+    }
+}
+
+fn not_found(path: &str) -> RadosError {
+    RadosError::new(format!("{}: No such file or directory", path))
+}
+
+// "/a/b/", "a//b" and "/a/c/../b" all become "/a/b".  .. at the root stays
+// at the root.
+fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+fn parent_of(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(idx) => &path[..idx],
+    }
+}
+
+/// An in-memory FileSystem for tests.  Clones share the same tree.
+#[derive(Clone)]
+pub struct MemFs {
+    tree: Arc<Mutex<MemTree>>,
+}
+
+impl MemFs {
+    pub fn new() -> MemFs {
+        let mut nodes = BTreeMap::new();
+        let now = now_secs();
+        nodes.insert("/".to_string(),
+                     MemNode {
+                         data: MemData::Dir,
+                         metadata: Metadata {
+                             ino: 1,
+                             mode: S_IFDIR | 0o755,
+                             nlink: 1,
+                             uid: 0,
+                             gid: 0,
+                             len: 0,
+                             atime: now,
+                             mtime: now,
+                             ctime: now,
+                         },
+                         xattrs: BTreeMap::new(),
+                     });
+        MemFs {
+            tree: Arc::new(Mutex::new(MemTree {
+                nodes: nodes,
+                next_ino: 2,
+            })),
+        }
+    }
+}
+
+impl Default for MemFs {
+    fn default() -> MemFs {
+        MemFs::new()
+    }
+}
+
+/// An open MemFs file.  It keeps the data alive after the file is removed,
+/// like an open file on disk.
+pub struct MemFile {
+    data: Arc<Mutex<Vec<u8>>>,
+    pos: u64,
+    readable: bool,
+    writable: bool,
+    append: bool,
+}
+
+// Extend data to len bytes, failing instead of aborting when that much
+// can't be had
+fn grow(data: &mut Vec<u8>, len: u64) -> io::Result<()> {
+    if len > isize::max_value() as u64 {
+        return Err(io::Error::from_raw_os_error(EFBIG));
+    }
+    let len = len as usize;
+    if len > data.len() {
+        let more = len - data.len();
+        if data.try_reserve(more).is_err() {
+            return Err(io::Error::from_raw_os_error(EFBIG));
+        }
+        data.resize(len, 0);
+    }
+    Ok(())
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.readable {
+            return Err(io::Error::from_raw_os_error(::libc::EBADF));
+        }
+        let data = self.data.lock().unwrap();
+        let mut cursor = Cursor::new(&data[..]);
+        cursor.set_position(self.pos);
+        let read = try!(cursor.read(buf));
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.writable {
+            return Err(io::Error::from_raw_os_error(::libc::EBADF));
+        }
+        let mut data = self.data.lock().unwrap();
+        if self.append {
+            self.pos = data.len() as u64;
+        }
+        let end = match self.pos.checked_add(buf.len() as u64) {
+            Some(end) => end,
+            None => return Err(io::Error::from_raw_os_error(EFBIG)),
+        };
+        try!(grow(&mut data, end));
+        data[self.pos as usize..end as usize].copy_from_slice(buf);
+        self.pos = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(offset) => (self.pos, offset),
+            SeekFrom::End(offset) => (self.data.lock().unwrap().len() as u64, offset),
+        };
+        let target = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        };
+        match target {
+            Some(target) => {
+                self.pos = target;
+                Ok(target)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek")),
+        }
+    }
+}
+
+impl FileSystem for MemFs {
+    type File = MemFile;
+
+    fn open(&self, path: &str, flags: i32, mode: mode_t) -> Result<MemFile, RadosError> {
+        let path = normalize(path);
+        let mut tree = self.tree.lock().unwrap();
+        if !tree.nodes.contains_key(&path) {
+            if flags & O_CREAT == 0 {
+                return Err(not_found(&path));
+            }
+            try!(tree.insert(&path, MemData::File(Arc::new(Mutex::new(Vec::new()))), mode as u32));
+        } else if flags & O_CREAT != 0 && flags & O_EXCL != 0 {
+            return Err(RadosError::new(format!("{}: File exists", path)));
+        }
+        let access = flags & O_ACCMODE;
+        let node = try!(tree.node(&path));
+        let data = match node.data {
+            MemData::File(ref data) => data.clone(),
+            MemData::Dir => return Err(RadosError::new(format!("{}: Is a directory", path))),
+        };
+        if flags & O_TRUNC != 0 && access != O_RDONLY {
+            data.lock().unwrap().clear();
+        }
+        Ok(MemFile {
+            data: data,
+            pos: 0,
+            readable: access != O_WRONLY,
+            writable: access != O_RDONLY,
+            append: flags & O_APPEND != 0,
+        })
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, RadosError> {
+        let path = normalize(path);
+        let tree = self.tree.lock().unwrap();
+        let node = try!(tree.node(&path));
+        let mut metadata = node.metadata.clone();
+        if let MemData::File(ref data) = node.data {
+            metadata.len = data.lock().unwrap().len() as u64;
+        }
+        Ok(metadata)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, RadosError> {
+        let path = normalize(path);
+        let entries = {
+            let tree = self.tree.lock().unwrap();
+            if !try!(tree.node(&path)).metadata.is_dir() {
+                return Err(RadosError::new(format!("{}: Not a directory", path)));
+            }
+            tree.children(&path)
+                .into_iter()
+                .map(|(child, _)| child.clone())
+                .collect::<Vec<String>>()
+        };
+        let mut result = Vec::with_capacity(entries.len());
+        for child in entries {
+            let name = OsString::from(&child[child.rfind('/').unwrap() + 1..]);
+            result.push(DirEntry {
+                name: name,
+                metadata: try!(self.metadata(&child)),
+            });
+        }
+        Ok(result)
+    }
+
+    fn create_dir_all(&self, path: &str) -> Result<(), RadosError> {
+        let path = normalize(path);
+        let mut tree = self.tree.lock().unwrap();
+        let mut current = String::new();
+        for part in path.split('/').filter(|part| !part.is_empty()) {
+            current.push('/');
+            current.push_str(part);
+            let is_dir = tree.nodes.get(&current).map(|node| node.metadata.is_dir());
+            match is_dir {
+                Some(true) => {}
+                Some(false) => {
+                    return Err(RadosError::new(format!("{}: Not a directory", current)));
+                }
+                None => try!(tree.insert(&current, MemData::Dir, 0o755)),
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> Result<(), RadosError> {
+        let path = normalize(path);
+        if path == "/" {
+            return Err(RadosError::new("/: Device or resource busy".to_string()));
+        }
+        let mut tree = self.tree.lock().unwrap();
+        if try!(tree.node(&path)).metadata.is_dir() && !tree.children(&path).is_empty() {
+            return Err(RadosError::new(format!("{}: Directory not empty", path)));
+        }
+        tree.nodes.remove(&path);
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), RadosError> {
+        let (from, to) = (normalize(from), normalize(to));
+        if from == to {
+            return Ok(());
+        }
+        if to.starts_with(&format!("{}/", from)) {
+            return Err(RadosError::new(format!("{}: Invalid argument", to)));
+        }
+        let mut tree = self.tree.lock().unwrap();
+        let from_dir = try!(tree.node(&from)).metadata.is_dir();
+        if !try!(tree.node(parent_of(&to))).metadata.is_dir() {
+            return Err(RadosError::new(format!("{}: Not a directory", parent_of(&to))));
+        }
+        // Like rename(2), an existing target is replaced if it is the same
+        // kind and, for a directory, empty
+        let to_dir = tree.nodes.get(&to).map(|node| node.metadata.is_dir());
+        match to_dir {
+            Some(true) if !from_dir => {
+                return Err(RadosError::new(format!("{}: Is a directory", to)));
+            }
+            Some(false) if from_dir => {
+                return Err(RadosError::new(format!("{}: Not a directory", to)));
+            }
+            Some(true) if !tree.children(&to).is_empty() => {
+                return Err(RadosError::new(format!("{}: Directory not empty", to)));
+            }
+            _ => {}
+        }
+        // Move the node and, for a directory, everything below it
+        let prefix = format!("{}/", from);
+        let moving: Vec<String> = tree.nodes
+            .keys()
+            .filter(|path| **path == from || path.starts_with(&prefix))
+            .cloned()
+            .collect();
+        tree.nodes.remove(&to);
+        for path in moving {
+            let node = tree.nodes.remove(&path).unwrap();
+            let new_path = format!("{}{}", to, &path[from.len()..]);
+            tree.nodes.insert(new_path, node);
+        }
+        Ok(())
+    }
+
+    fn get_xattr(&self, path: &str, name: &str) -> Result<Option<Vec<u8>>, RadosError> {
+        let path = normalize(path);
+        let tree = self.tree.lock().unwrap();
+        Ok(try!(tree.node(&path)).xattrs.get(name).cloned())
+    }
+
+    fn set_xattr(&self, path: &str, name: &str, value: &[u8]) -> Result<(), RadosError> {
+        let path = normalize(path);
+        let mut tree = self.tree.lock().unwrap();
+        try!(tree.node_mut(&path)).xattrs.insert(name.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn list_xattrs(&self, path: &str) -> Result<Vec<OsString>, RadosError> {
+        let path = normalize(path);
+        let tree = self.tree.lock().unwrap();
+        Ok(try!(tree.node(&path)).xattrs.keys().map(OsString::from).collect())
+    }
+
+    fn remove_xattr(&self, path: &str, name: &str) -> Result<(), RadosError> {
+        let path = normalize(path);
+        let mut tree = self.tree.lock().unwrap();
+        match try!(tree.node_mut(&path)).xattrs.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(RadosError::new(format!("{}: No data available", path))),
+        }
+    }
+
+    fn set_attr(&self, path: &str, attr: &SetAttr) -> Result<(), RadosError> {
+        let path = normalize(path);
+        let mut tree = self.tree.lock().unwrap();
+        let node = try!(tree.node_mut(&path));
+        if let Some(size) = attr.size {
+            match node.data {
+                MemData::File(ref data) => {
+                    let mut data = data.lock().unwrap();
+                    if size < data.len() as u64 {
+                        data.truncate(size as usize);
+                    } else {
+                        try!(grow(&mut data, size).map_err(|e| io_error(&path, e)));
+                    }
+                }
+                MemData::Dir => return Err(RadosError::new(format!("{}: Is a directory", path))),
+            }
+        }
+        let metadata = &mut node.metadata;
+        if let Some(mode) = attr.mode {
+            metadata.mode = (metadata.mode & S_IFMT) | (mode & 0o7777);
+        }
+        if let Some(uid) = attr.uid {
+            metadata.uid = uid;
+        }
+        if let Some(gid) = attr.gid {
+            metadata.gid = gid;
+        }
+        if let Some(atime) = attr.atime {
+            metadata.atime = atime;
+        }
+        if let Some(mtime) = attr.mtime {
+            metadata.mtime = mtime;
+        }
+        metadata.ctime = now_secs();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::O_RDWR;
+    use std::env;
+    use std::process;
+
+    fn write_file<F: FileSystem>(fs: &F, path: &str, data: &[u8]) {
+        let mut file = fs.open(path, O_CREAT | O_WRONLY | O_TRUNC, 0o644).unwrap();
+        file.write_all(data).unwrap();
+    }
+
+    fn read_file<F: FileSystem>(fs: &F, path: &str) -> Vec<u8> {
+        let mut file = fs.open(path, O_RDONLY, 0).unwrap();
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    fn names<F: FileSystem>(fs: &F, path: &str) -> Vec<String> {
+        let mut names: Vec<String> = fs.read_dir(path)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name.to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    // Local filesystems may add security.* xattrs of their own
+    fn user_xattrs<F: FileSystem>(fs: &F, path: &str) -> Vec<String> {
+        let mut names: Vec<String> = fs.list_xattrs(path)
+            .unwrap()
+            .into_iter()
+            .map(|name| name.to_string_lossy().into_owned())
+            .filter(|name| name.starts_with("user."))
+            .collect();
+        names.sort();
+        names
+    }
+
+    // Every backend is expected to behave the same way
+    fn scenario<F: FileSystem>(fs: &F) {
+        {
+            let mut file = fs.open("/file", O_CREAT | O_RDWR, 0o644).unwrap();
+            file.write_all(b"hello world").unwrap();
+            assert_eq!(file.seek(SeekFrom::Start(6)).unwrap(), 6);
+            let mut buf = [0; 5];
+            file.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"world");
+            assert_eq!(file.seek(SeekFrom::End(-5)).unwrap(), 6);
+            file.write_all(b"there").unwrap();
+            assert_eq!(file.seek(SeekFrom::Current(-11)).unwrap(), 0);
+        }
+        assert_eq!(read_file(fs, "/file"), b"hello there".to_vec());
+        {
+            let mut file = fs.open("/file", O_WRONLY | O_APPEND, 0).unwrap();
+            file.write_all(b"!").unwrap();
+        }
+        assert_eq!(read_file(fs, "/file"), b"hello there!".to_vec());
+        let metadata = fs.metadata("/file").unwrap();
+        assert!(metadata.is_file());
+        assert_eq!(metadata.len, 12);
+        assert!(fs.open("/missing", O_RDONLY, 0).is_err());
+        assert!(fs.open("/file", O_CREAT | O_EXCL | O_WRONLY, 0o644).is_err());
+
+        fs.create_dir_all("/a/b/c").unwrap();
+        fs.create_dir_all("/a/b").unwrap();
+        assert!(fs.metadata("/a/b/c").unwrap().is_dir());
+        write_file(fs, "/a/one", b"1");
+        write_file(fs, "/a/b/two", b"2");
+        assert_eq!(names(fs, "/a"), vec!["b", "one"]);
+        assert_eq!(names(fs, "/a/b/"), vec!["c", "two"]);
+        assert!(fs.create_dir_all("/a/one/x").is_err());
+        assert!(fs.read_dir("/a/one").is_err());
+
+        assert!(fs.remove("/a/b").is_err());
+        assert!(fs.metadata("/a/b/two").is_ok());
+        fs.remove("/a/b/c").unwrap();
+        fs.remove("/a/b/two").unwrap();
+        fs.remove("/a/b").unwrap();
+        assert!(fs.metadata("/a/b").is_err());
+        assert!(fs.remove("/a/b").is_err());
+
+        write_file(fs, "/a/two", b"2");
+        fs.rename("/a/one", "/a/two").unwrap();
+        assert!(fs.metadata("/a/one").is_err());
+        assert_eq!(read_file(fs, "/a/two"), b"1".to_vec());
+        fs.create_dir_all("/d/e").unwrap();
+        assert!(fs.rename("/a/two", "/d/e").is_err());
+        assert!(fs.rename("/a", "/d").is_err());
+        fs.rename("/a", "/d/e").unwrap();
+        assert_eq!(names(fs, "/d/e"), vec!["two"]);
+        assert_eq!(names(fs, "/"), vec!["d", "file"]);
+
+        assert_eq!(fs.get_xattr("/file", "user.test").unwrap(), None);
+        assert!(user_xattrs(fs, "/file").is_empty());
+        fs.set_xattr("/file", "user.test", b"value").unwrap();
+        fs.set_xattr("/file", "user.empty", b"").unwrap();
+        assert_eq!(fs.get_xattr("/file", "user.test").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(fs.get_xattr("/file", "user.empty").unwrap(), Some(Vec::new()));
+        assert_eq!(user_xattrs(fs, "/file"), vec!["user.empty", "user.test"]);
+        fs.remove_xattr("/file", "user.test").unwrap();
+        assert!(fs.remove_xattr("/file", "user.test").is_err());
+        assert_eq!(user_xattrs(fs, "/file"), vec!["user.empty"]);
+
+        // .. can't climb out of the root
+        write_file(fs, "/../../escaped", b"x");
+        assert_eq!(read_file(fs, "/escaped"), b"x".to_vec());
+        assert!(fs.metadata("/d/../file").unwrap().is_file());
+    }
+
+    #[test]
+    fn mem_fs() {
+        scenario(&MemFs::new());
+    }
+
+    #[test]
+    fn local_fs() {
+        let root = env::temp_dir().join(format!("cephfs-local-fs-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir(&root).unwrap();
+        scenario(&LocalFs::new(root.clone()));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn mem_fs_huge_sizes() {
+        let fs = MemFs::new();
+        let mut file = fs.open("/file", O_CREAT | O_RDWR, 0o644).unwrap();
+        file.seek(SeekFrom::Start(u64::max_value() - 1)).unwrap();
+        assert!(file.write(b"abc").is_err());
+        file.seek(SeekFrom::Start(1 << 62)).unwrap();
+        assert!(file.write(b"abc").is_err());
+        for size in vec![u64::max_value(), 1 << 62] {
+            let attr = SetAttr { size: Some(size), ..SetAttr::default() };
+            assert!(fs.set_attr("/file", &attr).is_err());
+        }
+        assert_eq!(fs.metadata("/file").unwrap().len, 0);
+        fs.set_attr("/file", &SetAttr { size: Some(3), ..SetAttr::default() }).unwrap();
+        assert_eq!(read_file(&fs, "/file"), vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn normalize_paths() {
+        assert_eq!(normalize("/a/b/"), "/a/b");
+        assert_eq!(normalize("a//b"), "/a/b");
+        assert_eq!(normalize("/a/./c/../b"), "/a/b");
+        assert_eq!(normalize("/../../etc"), "/etc");
+        assert_eq!(normalize(".."), "/");
+    }
+}
@@ -1,4 +1,19 @@
 extern crate libc;
+#[cfg(feature = "tokio")]
+extern crate futures;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[macro_use]
+extern crate serde_json;
 
+#[cfg(feature = "tokio")]
+pub mod async_io;
 pub mod cephfs;
 pub mod cephfs_sys;
+pub mod distributed_lock;
+pub mod fs;
+pub mod mds;
+#[cfg(feature = "nonblocking-io")]
+pub mod nonblocking;
+pub mod pin;
+pub mod striping;
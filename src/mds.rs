@@ -0,0 +1,282 @@
+//! Typed wrappers over the MDS admin commands sent with mds_command.
+extern crate ceph_rust;
+use self::ceph_rust::ceph::RadosError;
+use cephfs::{mds_command, MdsCommandOutput, MdsSpec};
+use cephfs_sys::ceph_mount_info;
+use serde_json::{Deserializer, Value};
+
+/// A client session as reported by `session ls`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Session {
+    /// The client id, as used by evict_client
+    pub id: u64,
+    pub address: String,
+    /// open, closing, stale, killing etc
+    pub state: String,
+    pub num_caps: u64,
+    /// The root the client mounted, if it told the MDS
+    pub root: Option<String>,
+    pub hostname: Option<String>,
+    /// The client entity name without the "client." prefix
+    pub entity_id: Option<String>,
+}
+
+/// Size of the MDS metadata cache from `cache status`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStatus {
+    pub items: u64,
+    pub bytes: u64,
+}
+
+/// A request the MDS is currently working on, from `dump_ops_in_flight`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OpInFlight {
+    pub description: String,
+    pub initiated_at: String,
+    /// Seconds since the op was received
+    pub age: f64,
+    /// Seconds the op has been running for
+    pub duration: f64,
+    /// The last point the op reached, for example "failed to rdlock, waiting"
+    pub flag_point: Option<String>,
+    /// The client that sent the op, for example "client.4305"
+    pub client: Option<String>,
+}
+
+/// Decode every JSON document in an MDS reply.  A command sent to
+/// MdsSpec::All gets one document back per MDS, one after another.
+fn decode_replies(output: &MdsCommandOutput) -> Result<Vec<Value>, RadosError> {
+    let mut replies = Vec::new();
+    for reply in Deserializer::from_slice(&output.data).into_iter::<Value>() {
+        let reply = try!(reply.map_err(|e| {
+            RadosError::new(format!("Unable to decode mds output: {}", e))
+        }));
+        replies.push(reply);
+    }
+    Ok(replies)
+}
+
+fn get_u64(value: &Value, pointer: &str) -> u64 {
+    value.pointer(pointer).and_then(|v| v.as_u64()).unwrap_or(0)
+}
+
+fn get_f64(value: &Value, pointer: &str) -> f64 {
+    value.pointer(pointer).and_then(|v| v.as_f64()).unwrap_or(0.0)
+}
+
+fn get_string(value: &Value, pointer: &str) -> Option<String> {
+    value.pointer(pointer).and_then(|v| v.as_str()).map(|s| s.to_string())
+}
+
+fn parse_session(value: &Value) -> Result<Session, RadosError> {
+    let id = match value.pointer("/id").and_then(|v| v.as_u64()) {
+        Some(id) => id,
+        None => return Err(RadosError::new(format!("Session without an id: {}", value))),
+    };
+    // Newer releases split the address out, older ones only have the
+    // "client.4305 10.0.0.1:0/3234" instance string
+    let address = get_string(value, "/entity/addr/addr")
+        .or_else(|| {
+            get_string(value, "/inst")
+                .and_then(|inst| inst.splitn(2, ' ').nth(1).map(|s| s.to_string()))
+        })
+        .unwrap_or_default();
+    Ok(Session {
+        id: id,
+        address: address,
+        state: get_string(value, "/state").unwrap_or_default(),
+        num_caps: get_u64(value, "/num_caps"),
+        root: get_string(value, "/client_metadata/root"),
+        hostname: get_string(value, "/client_metadata/hostname"),
+        entity_id: get_string(value, "/client_metadata/entity_id"),
+    })
+}
+
+fn parse_op(value: &Value) -> OpInFlight {
+    OpInFlight {
+        description: get_string(value, "/description").unwrap_or_default(),
+        initiated_at: get_string(value, "/initiated_at").unwrap_or_default(),
+        age: get_f64(value, "/age"),
+        duration: get_f64(value, "/duration"),
+        flag_point: get_string(value, "/type_data/flag_point"),
+        client: get_string(value, "/type_data/client_info/client"),
+    }
+}
+
+pub fn list_sessions(cmount: &mut ceph_mount_info,
+                     mds: MdsSpec)
+                     -> Result<Vec<Session>, RadosError> {
+    let output = try!(mds_command(cmount, mds, json!({"prefix": "session ls"}), &[]));
+    let mut sessions = Vec::new();
+    for reply in try!(decode_replies(&output)) {
+        match reply.as_array() {
+            Some(list) => {
+                for session in list {
+                    sessions.push(try!(parse_session(session)));
+                }
+            }
+            None => {
+                return Err(RadosError::new(format!("Expected a list of sessions, got: {}", reply)))
+            }
+        }
+    }
+    Ok(sessions)
+}
+
+/// Evict a client by its session id.  The client is blacklisted and will
+/// have to remount.
+pub fn evict_client(cmount: &mut ceph_mount_info,
+                    mds: MdsSpec,
+                    client_id: u64)
+                    -> Result<(), RadosError> {
+    let cmd = json!({
+        "prefix": "client evict",
+        "filters": [format!("id={}", client_id)],
+    });
+    try!(mds_command(cmount, mds, cmd, &[]));
+    Ok(())
+}
+
+/// The cache size of the MDS.  With MdsSpec::All this is the total over
+/// every MDS that replied.
+pub fn cache_status(cmount: &mut ceph_mount_info, mds: MdsSpec) -> Result<CacheStatus, RadosError> {
+    let output = try!(mds_command(cmount, mds, json!({"prefix": "cache status"}), &[]));
+    let mut status = CacheStatus::default();
+    for reply in try!(decode_replies(&output)) {
+        status.items += get_u64(&reply, "/pool/items");
+        status.bytes += get_u64(&reply, "/pool/bytes");
+    }
+    Ok(status)
+}
+
+pub fn dump_ops_in_flight(cmount: &mut ceph_mount_info,
+                          mds: MdsSpec)
+                          -> Result<Vec<OpInFlight>, RadosError> {
+    let output = try!(mds_command(cmount, mds, json!({"prefix": "dump_ops_in_flight"}), &[]));
+    let mut ops = Vec::new();
+    for reply in try!(decode_replies(&output)) {
+        if let Some(list) = reply.pointer("/ops").and_then(|v| v.as_array()) {
+            ops.extend(list.iter().map(parse_op));
+        }
+    }
+    Ok(ops)
+}
+
+/// Options for scrub_start.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScrubOptions {
+    /// Scrub everything below the path
+    pub recursive: bool,
+    /// Repair any damage that is found
+    pub repair: bool,
+    /// Scrub even if the inodes were scrubbed recently
+    pub force: bool,
+}
+
+impl ScrubOptions {
+    fn scrubops(&self) -> Vec<&'static str> {
+        let mut ops = Vec::new();
+        if self.recursive {
+            ops.push("recursive");
+        }
+        if self.repair {
+            ops.push("repair");
+        }
+        if self.force {
+            ops.push("force");
+        }
+        ops
+    }
+}
+
+/// The reply to scrub_start.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScrubStarted {
+    /// The tag the scrub is tracked under in scrub_status.  Older releases
+    /// don't report one.
+    pub tag: Option<String>,
+}
+
+/// A scrub listed by scrub_status.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScrubInfo {
+    pub tag: String,
+    pub path: String,
+    pub options: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScrubStatus {
+    /// The human readable status line from the MDS
+    pub summary: String,
+    pub active: bool,
+    pub paused: bool,
+    pub scrubs: Vec<ScrubInfo>,
+}
+
+// Scrubs are driven from rank 0, which passes work on to the other ranks
+fn scrub_command(cmount: &mut ceph_mount_info, cmd: Value) -> Result<Value, RadosError> {
+    let output = try!(mds_command(cmount, MdsSpec::Rank(0), cmd, &[]));
+    if output.data.is_empty() {
+        return Ok(Value::Null);
+    }
+    let reply = try!(output.json());
+    let return_code = reply.pointer("/return_code").and_then(|v| v.as_i64()).unwrap_or(0);
+    if return_code < 0 {
+        return Err(RadosError::new(format!("Scrub command failed with {}: {}",
+                                           return_code,
+                                           output.status)));
+    }
+    Ok(reply)
+}
+
+/// Start a scrub of path.
+pub fn scrub_start(cmount: &mut ceph_mount_info,
+                   path: &str,
+                   options: ScrubOptions)
+                   -> Result<ScrubStarted, RadosError> {
+    let cmd = json!({
+        "prefix": "scrub start",
+        "path": path,
+        "scrubops": options.scrubops(),
+    });
+    let reply = try!(scrub_command(cmount, cmd));
+    Ok(ScrubStarted { tag: get_string(&reply, "/scrub_tag") })
+}
+
+pub fn scrub_status(cmount: &mut ceph_mount_info) -> Result<ScrubStatus, RadosError> {
+    let reply = try!(scrub_command(cmount, json!({"prefix": "scrub status"})));
+    let summary = get_string(&reply, "/status").unwrap_or_default();
+    let mut scrubs = Vec::new();
+    if let Some(map) = reply.pointer("/scrubs").and_then(|v| v.as_object()) {
+        for (tag, scrub) in map {
+            scrubs.push(ScrubInfo {
+                tag: tag.clone(),
+                path: get_string(scrub, "/path").unwrap_or_default(),
+                options: get_string(scrub, "/options").unwrap_or_default(),
+            });
+        }
+    }
+    let lower = summary.to_lowercase();
+    Ok(ScrubStatus {
+        active: !summary.is_empty() && !lower.contains("no active scrubs"),
+        paused: lower.contains("paused"),
+        summary: summary,
+        scrubs: scrubs,
+    })
+}
+
+pub fn scrub_pause(cmount: &mut ceph_mount_info) -> Result<(), RadosError> {
+    try!(scrub_command(cmount, json!({"prefix": "scrub pause"})));
+    Ok(())
+}
+
+pub fn scrub_resume(cmount: &mut ceph_mount_info) -> Result<(), RadosError> {
+    try!(scrub_command(cmount, json!({"prefix": "scrub resume"})));
+    Ok(())
+}
+
+pub fn scrub_abort(cmount: &mut ceph_mount_info) -> Result<(), RadosError> {
+    try!(scrub_command(cmount, json!({"prefix": "scrub abort"})));
+    Ok(())
+}
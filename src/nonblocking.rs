@@ -0,0 +1,204 @@
+//! Reads and writes on an FhHandle that complete from a libcephfs callback
+//! instead of holding a thread, using ceph_ll_nonblocking_readv_writev.
+//! Needs libcephfs 10.0.3 or later and the `nonblocking-io` feature.
+//!
+//! The futures work with any executor.  Dropping one before it completes
+//! doesn't cancel the IO, the handle waits for it before closing.
+//!
+//! When starting an IO fails, Reef (18.2) returns the error without calling
+//! the callback, while later releases may call the callback with the error
+//! and return either 0 or the error as well.  Either way any callback for a
+//! failed start has run by the time the call returns, which is all this
+//! relies on.
+extern crate ceph_rust;
+use self::ceph_rust::ceph::RadosError;
+use cephfs::{get_error, FhHandle};
+use cephfs_sys::*;
+
+use libc::{c_void, iovec};
+use std::future::Future;
+use std::pin::Pin;
+use std::ptr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+
+// Counts the IOs in flight on an FhHandle
+#[derive(Default)]
+pub(crate) struct InFlight {
+    count: Mutex<usize>,
+    idle: Condvar,
+}
+
+impl InFlight {
+    fn start(&self) {
+        *self.count.lock().unwrap() += 1;
+    }
+
+    fn finish(&self) {
+        let mut count = self.count.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.idle.notify_all();
+        }
+    }
+
+    // Block until every IO has completed
+    pub(crate) fn wait_idle(&self) {
+        let mut count = self.count.lock().unwrap();
+        while *count > 0 {
+            count = self.idle.wait(count).unwrap();
+        }
+    }
+}
+
+// Filled in by the callback
+struct OpState {
+    result: Option<(i64, Vec<u8>)>,
+    waker: Option<Waker>,
+    // Set by the callback, after which the Op has been freed
+    done: bool,
+}
+
+// Everything the IO refers to, owned and freed by the callback once started
+struct Op {
+    info: ceph_ll_io_info,
+    iov: iovec,
+    buf: Vec<u8>,
+    state: Arc<Mutex<OpState>>,
+    inflight: Arc<InFlight>,
+}
+
+unsafe extern "C" fn io_done(info: *mut ceph_ll_io_info) {
+    let op = Box::from_raw((*info).priv_ as *mut Op);
+    let op = *op;
+    let waker = {
+        let mut state = op.state.lock().unwrap();
+        state.result = Some((op.info.result, op.buf));
+        state.done = true;
+        state.waker.take()
+    };
+    op.inflight.finish();
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+// The caller's side of an IO.  Yields the buffer and the bytes transferred.
+struct Io {
+    state: Arc<Mutex<OpState>>,
+}
+
+impl Io {
+    fn poll_io(&self, cx: &mut Context) -> Poll<Result<(Vec<u8>, usize), RadosError>> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some((result, _)) if result < 0 => {
+                Poll::Ready(get_error(result as i32).and_then(|e| Err(RadosError::new(e))))
+            }
+            Some((result, buf)) => Poll::Ready(Ok((buf, result as usize))),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+fn start_io(fh: &FhHandle, mut buf: Vec<u8>, offset: u64, write: bool) -> Io {
+    let state = Arc::new(Mutex::new(OpState {
+        result: None,
+        waker: None,
+        done: false,
+    }));
+    if offset > i64::max_value() as u64 {
+        state.lock().unwrap().result = Some((-(::libc::EINVAL as i64), buf));
+        return Io { state: state };
+    }
+    let iov = iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+    // The Vec's heap buffer doesn't move when the Vec does, so iov stays good
+    let op = Box::into_raw(Box::new(Op {
+        info: ceph_ll_io_info {
+            callback: Some(io_done),
+            priv_: ptr::null_mut(),
+            fh: fh.fh,
+            iov: ptr::null(),
+            iovcnt: 1,
+            off: offset as i64,
+            result: 0,
+            write: write,
+            fsync: false,
+            syncdataonly: false,
+        },
+        iov: iov,
+        buf: buf,
+        state: state.clone(),
+        inflight: fh.inflight.clone(),
+    }));
+    unsafe {
+        (*op).info.priv_ = op as *mut c_void;
+        (*op).info.iov = &(*op).iov;
+        fh.inflight.start();
+        let ret_code = ceph_ll_nonblocking_readv_writev(fh.cmount, &mut (*op).info);
+        if ret_code < 0 {
+            let mut locked = state.lock().unwrap();
+            if !locked.done {
+                // The callback never ran and won't now, so the op is ours
+                let op = *Box::from_raw(op);
+                op.inflight.finish();
+                locked.result = Some((ret_code, op.buf));
+            }
+        }
+    }
+    Io { state: state }
+}
+
+impl FhHandle {
+    /// Read up to len bytes from offset.
+    pub fn read_at(&self, offset: u64, len: usize) -> ReadAt {
+        ReadAt { io: start_io(self, vec![0; len], offset, false) }
+    }
+
+    /// Write data at offset.
+    pub fn write_at(&self, offset: u64, data: Vec<u8>) -> WriteAt {
+        WriteAt { io: start_io(self, data, offset, true) }
+    }
+}
+
+/// The bytes read by FhHandle::read_at.
+pub struct ReadAt {
+    io: Io,
+}
+
+impl Future for ReadAt {
+    type Output = Result<Vec<u8>, RadosError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Vec<u8>, RadosError>> {
+        match self.io.poll_io(cx) {
+            Poll::Ready(Ok((mut buf, read))) => {
+                buf.truncate(read);
+                Poll::Ready(Ok(buf))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// The number of bytes written by FhHandle::write_at.
+pub struct WriteAt {
+    io: Io,
+}
+
+impl Future for WriteAt {
+    type Output = Result<usize, RadosError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<usize, RadosError>> {
+        match self.io.poll_io(cx) {
+            Poll::Ready(result) => Poll::Ready(result.map(|(_, written)| written)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
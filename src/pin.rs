@@ -0,0 +1,240 @@
+//! Subtree pinning of directories to MDS ranks through the ceph.dir.pin
+//! virtual xattrs.
+extern crate ceph_rust;
+use self::ceph_rust::ceph::RadosError;
+use cephfs::{get_error, getxattr, setxattr};
+use cephfs_sys::*;
+
+use libc::{c_void, DT_DIR, EINVAL, ENODATA, EOPNOTSUPP};
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::ptr;
+
+const PIN: &'static str = "ceph.dir.pin";
+const PIN_DISTRIBUTED: &'static str = "ceph.dir.pin.distributed";
+const PIN_RANDOM: &'static str = "ceph.dir.pin.random";
+
+/// How a directory's subtree is assigned to MDS ranks.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PinPolicy {
+    /// Export pin the whole subtree to a rank
+    Rank(u32),
+    /// Spread the immediate children over all ranks
+    Distributed,
+    /// Pin each descendant directory to a random rank with this probability
+    Random(f64),
+    /// No pin, the balancer decides
+    None,
+}
+
+/// One directory found by export_pins_report.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PinReport {
+    pub path: String,
+    /// The policy set on this directory itself
+    pub policy: PinPolicy,
+    /// The policy in effect, which may be inherited from a parent
+    pub effective: PinPolicy,
+}
+
+fn set_pin_xattr(cmount: &mut ceph_mount_info,
+                 path: &str,
+                 name: &str,
+                 value: &str)
+                 -> Result<(), RadosError> {
+    setxattr(cmount, path, name, value.as_bytes(), value.len(), 0)
+}
+
+/// Clear one of the ephemeral pin xattrs.  Releases before the ephemeral
+/// pins reject them as unknown, which is fine as there is nothing to clear.
+fn clear_ephemeral_pin(cmount: &mut ceph_mount_info,
+                       path: &str,
+                       name: &str)
+                       -> Result<(), RadosError> {
+    let c_path = try!(CString::new(path));
+    let c_name = try!(CString::new(name));
+    unsafe {
+        let ret_code = ceph_setxattr(cmount,
+                                     c_path.as_ptr(),
+                                     c_name.as_ptr(),
+                                     "0".as_ptr() as *const c_void,
+                                     1,
+                                     0);
+        if ret_code == -EINVAL || ret_code == -ENODATA || ret_code == -EOPNOTSUPP {
+            return Ok(());
+        }
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+    }
+    Ok(())
+}
+
+/// Set the pin policy of a directory.  Setting PinPolicy::None clears all
+/// three kinds of pin.
+pub fn pin_dir(cmount: &mut ceph_mount_info,
+               path: &str,
+               policy: PinPolicy)
+               -> Result<(), RadosError> {
+    match policy {
+        PinPolicy::Rank(rank) => set_pin_xattr(cmount, path, PIN, &rank.to_string()),
+        PinPolicy::Distributed => set_pin_xattr(cmount, path, PIN_DISTRIBUTED, "1"),
+        PinPolicy::Random(probability) => {
+            if !(probability >= 0.0 && probability <= 1.0) {
+                return Err(RadosError::new(format!("Random pin probability {} is not \
+                                                    between 0 and 1",
+                                                   probability)));
+            }
+            set_pin_xattr(cmount, path, PIN_RANDOM, &probability.to_string())
+        }
+        PinPolicy::None => {
+            try!(set_pin_xattr(cmount, path, PIN, "-1"));
+            try!(clear_ephemeral_pin(cmount, path, PIN_DISTRIBUTED));
+            clear_ephemeral_pin(cmount, path, PIN_RANDOM)
+        }
+    }
+}
+
+/// The pin policy set on a directory itself.  An export pin wins over the
+/// distributed and random policies, as it does in the MDS.
+pub fn get_pin(cmount: &mut ceph_mount_info, path: &str) -> Result<PinPolicy, RadosError> {
+    let rank = try!(getxattr(cmount, path, PIN));
+    match rank.trim().parse::<i64>() {
+        Ok(rank) if rank >= 0 => return Ok(PinPolicy::Rank(rank as u32)),
+        Ok(_) => {}
+        Err(e) => {
+            return Err(RadosError::new(format!("Unable to parse {} {:?}: {}", PIN, rank, e)))
+        }
+    }
+    // Releases before the ephemeral pins don't have these xattrs at all
+    if let Ok(distributed) = getxattr(cmount, path, PIN_DISTRIBUTED) {
+        if distributed.trim() == "1" {
+            return Ok(PinPolicy::Distributed);
+        }
+    }
+    if let Ok(random) = getxattr(cmount, path, PIN_RANDOM) {
+        if let Ok(probability) = random.trim().parse::<f64>() {
+            if probability > 0.0 {
+                return Ok(PinPolicy::Random(probability));
+            }
+        }
+    }
+    Ok(PinPolicy::None)
+}
+
+fn join_path(parent: &str, name: &str) -> String {
+    if parent.ends_with('/') {
+        format!("{}{}", parent, name)
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+/// The names of the subdirectories of path.
+fn subdirs(cmount: &mut ceph_mount_info, path: &str) -> Result<Vec<String>, RadosError> {
+    let c_path = try!(CString::new(path));
+    let mut dirs = Vec::new();
+    unsafe {
+        let mut dirp: *mut ceph_dir_result = ptr::null_mut();
+        let ret_code = ceph_opendir(cmount, c_path.as_ptr(), &mut dirp);
+        if ret_code < 0 {
+            return Err(RadosError::new(try!(get_error(ret_code))));
+        }
+        loop {
+            let mut de: dirent = mem::zeroed();
+            let ret_code = ceph_readdir_r(cmount, dirp, &mut de);
+            if ret_code < 0 {
+                ceph_closedir(cmount, dirp);
+                return Err(RadosError::new(try!(get_error(ret_code))));
+            }
+            if ret_code == 0 {
+                break;
+            }
+            let name = CStr::from_ptr(de.d_name.as_ptr()).to_string_lossy().into_owned();
+            if de.d_type == DT_DIR && name != "." && name != ".." {
+                dirs.push(name);
+            }
+        }
+        ceph_closedir(cmount, dirp);
+    }
+    Ok(dirs)
+}
+
+/// What a directory passes on to its children.  A distributed pin only
+/// spreads the immediate children of the directory it is set on, so it is
+/// not passed on by a child that inherited it.
+fn passed_on(policy: PinPolicy, effective: PinPolicy) -> PinPolicy {
+    if policy == PinPolicy::None && effective == PinPolicy::Distributed {
+        PinPolicy::None
+    } else {
+        effective
+    }
+}
+
+/// The policy in effect at path, looking at its ancestors when nothing is
+/// set on path itself.
+fn inherited_pin(cmount: &mut ceph_mount_info, path: &str) -> Result<PinPolicy, RadosError> {
+    let mut current = path.trim_end_matches('/').to_string();
+    let mut depth = 0;
+    loop {
+        let lookup = if current.is_empty() { "/" } else { current.as_str() };
+        let policy = try!(get_pin(cmount, lookup));
+        if policy == PinPolicy::Distributed && depth > 1 {
+            // Only reaches as far as the children of lookup, path is
+            // further down, under one of their ephemeral pins
+            return Ok(PinPolicy::None);
+        }
+        if policy != PinPolicy::None || current.is_empty() {
+            return Ok(policy);
+        }
+        depth += 1;
+        match current.rfind('/') {
+            Some(idx) => current.truncate(idx),
+            None => return Ok(PinPolicy::None),
+        }
+    }
+}
+
+fn walk_pins(cmount: &mut ceph_mount_info,
+             path: &str,
+             inherited: PinPolicy,
+             report: &mut Vec<PinReport>)
+             -> Result<(), RadosError> {
+    for name in try!(subdirs(cmount, path)) {
+        let child = join_path(path, &name);
+        let policy = try!(get_pin(cmount, &child));
+        let effective = if policy == PinPolicy::None {
+            inherited
+        } else {
+            policy
+        };
+        if policy != PinPolicy::None {
+            report.push(PinReport {
+                path: child.clone(),
+                policy: policy,
+                effective: effective,
+            });
+        }
+        try!(walk_pins(cmount, &child, passed_on(policy, effective), report));
+    }
+    Ok(())
+}
+
+/// Walk the directory trees under roots and list each root along with
+/// every directory below them that has a pin set.
+pub fn export_pins_report(cmount: &mut ceph_mount_info,
+                          roots: &[&str])
+                          -> Result<Vec<PinReport>, RadosError> {
+    let mut report = Vec::new();
+    for root in roots {
+        let policy = try!(get_pin(cmount, root));
+        let effective = try!(inherited_pin(cmount, root));
+        report.push(PinReport {
+            path: root.to_string(),
+            policy: policy,
+            effective: effective,
+        });
+        try!(walk_pins(cmount, root, passed_on(policy, effective), &mut report));
+    }
+    Ok(report)
+}
@@ -0,0 +1,373 @@
+//! Maps byte ranges of a file to the RADOS objects that store them, following
+//! the same striping rules as the ceph client.  Objects in the data pool are
+//! named <inode number in hex>.<object number as %08x>.
+extern crate ceph_rust;
+use self::ceph_rust::ceph::RadosError;
+use cephfs::FileLayout;
+use cephfs_sys::ceph_file_layout;
+
+use std::cmp;
+
+/// A piece of a file range stored in one object.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObjectExtent {
+    pub object_name: String,
+    pub object_no: u64,
+    /// Offset within the object
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// A range of the file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FileExtent {
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ObjectMapper {
+    ino: u64,
+    stripe_unit: u64,
+    stripe_count: u64,
+    object_size: u64,
+}
+
+impl ObjectMapper {
+    pub fn new(ino: u64,
+               stripe_unit: u64,
+               stripe_count: u64,
+               object_size: u64)
+               -> Result<ObjectMapper, RadosError> {
+        if stripe_unit == 0 || stripe_count == 0 || object_size == 0 {
+            return Err(RadosError::new(format!("Layout sizes must all be non zero, got \
+                                                stripe_unit {} stripe_count {} object_size {}",
+                                               stripe_unit,
+                                               stripe_count,
+                                               object_size)));
+        }
+        if object_size % stripe_unit != 0 {
+            return Err(RadosError::new(format!("Object size {} is not a multiple of the \
+                                                stripe unit {}",
+                                               object_size,
+                                               stripe_unit)));
+        }
+        Ok(ObjectMapper {
+            ino: ino,
+            stripe_unit: stripe_unit,
+            stripe_count: stripe_count,
+            object_size: object_size,
+        })
+    }
+
+    pub fn from_layout(ino: u64, layout: &ceph_file_layout) -> Result<ObjectMapper, RadosError> {
+        ObjectMapper::new(ino,
+                          layout.fl_stripe_unit as u64,
+                          layout.fl_stripe_count as u64,
+                          layout.fl_object_size as u64)
+    }
+
+    pub fn from_file_layout(ino: u64, layout: &FileLayout) -> Result<ObjectMapper, RadosError> {
+        ObjectMapper::new(ino,
+                          layout.stripe_unit as u64,
+                          layout.stripe_count as u64,
+                          layout.object_size as u64)
+    }
+
+    pub fn object_name(&self, object_no: u64) -> String {
+        format!("{:x}.{:08x}", self.ino, object_no)
+    }
+
+    fn stripes_per_object(&self) -> u64 {
+        self.object_size / self.stripe_unit
+    }
+
+    /// The objects holding length bytes of the file from offset, in file
+    /// order.  Consecutive pieces that are contiguous in the same object are
+    /// merged.
+    pub fn file_to_objects(&self,
+                           offset: u64,
+                           length: u64)
+                           -> Result<Vec<ObjectExtent>, RadosError> {
+        if offset.checked_add(length).is_none() {
+            return Err(RadosError::new(format!("Range {}~{} is past the largest file offset",
+                                               offset,
+                                               length)));
+        }
+        let mut extents: Vec<ObjectExtent> = Vec::new();
+        let mut offset = offset;
+        let mut left = length;
+        while left > 0 {
+            let blockno = offset / self.stripe_unit;
+            let stripeno = blockno / self.stripe_count;
+            let stripepos = blockno % self.stripe_count;
+            let object_set = stripeno / self.stripes_per_object();
+            let object_no = object_set * self.stripe_count + stripepos;
+            let block_start = (stripeno % self.stripes_per_object()) * self.stripe_unit;
+            let block_offset = offset % self.stripe_unit;
+            let len = cmp::min(left, self.stripe_unit - block_offset);
+            let object_offset = block_start + block_offset;
+
+            let merged = match extents.last_mut() {
+                Some(last) if last.object_no == object_no &&
+                              last.offset + last.length == object_offset => {
+                    last.length += len;
+                    true
+                }
+                _ => false,
+            };
+            if !merged {
+                extents.push(ObjectExtent {
+                    object_name: self.object_name(object_no),
+                    object_no: object_no,
+                    offset: object_offset,
+                    length: len,
+                });
+            }
+            offset += len;
+            left -= len;
+        }
+        Ok(extents)
+    }
+
+    /// The ranges of the file stored in length bytes of an object from
+    /// offset, in object order.  Consecutive ranges that are contiguous in
+    /// the file are merged.
+    pub fn object_to_file(&self,
+                          object_no: u64,
+                          offset: u64,
+                          length: u64)
+                          -> Result<Vec<FileExtent>, RadosError> {
+        if offset.checked_add(length).map(|end| end > self.object_size).unwrap_or(true) {
+            return Err(RadosError::new(format!("Range {}~{} is past the end of a {} byte object",
+                                               offset,
+                                               length,
+                                               self.object_size)));
+        }
+        let object_set = object_no / self.stripe_count;
+        let stripepos = object_no % self.stripe_count;
+        let mut extents: Vec<FileExtent> = Vec::new();
+        let mut offset = offset;
+        let mut left = length;
+        while left > 0 {
+            let stripeno = object_set * self.stripes_per_object() + offset / self.stripe_unit;
+            let blockno = stripeno * self.stripe_count + stripepos;
+            let block_offset = offset % self.stripe_unit;
+            let len = cmp::min(left, self.stripe_unit - block_offset);
+            let file_offset = blockno * self.stripe_unit + block_offset;
+
+            let merged = match extents.last_mut() {
+                Some(last) if last.offset + last.length == file_offset => {
+                    last.length += len;
+                    true
+                }
+                _ => false,
+            };
+            if !merged {
+                extents.push(FileExtent {
+                    offset: file_offset,
+                    length: len,
+                });
+            }
+            offset += len;
+            left -= len;
+        }
+        Ok(extents)
+    }
+
+    /// Like object_to_file but starting from an object name.  The name must
+    /// belong to this mapper's inode.
+    pub fn object_name_to_file(&self,
+                               object_name: &str,
+                               offset: u64,
+                               length: u64)
+                               -> Result<Vec<FileExtent>, RadosError> {
+        let (ino, object_no) = try!(parse_object_name(object_name));
+        if ino != self.ino {
+            return Err(RadosError::new(format!("Object {} belongs to inode {:x}, not {:x}",
+                                               object_name,
+                                               ino,
+                                               self.ino)));
+        }
+        self.object_to_file(object_no, offset, length)
+    }
+}
+
+/// Split a data object name into its inode and object numbers.
+pub fn parse_object_name(object_name: &str) -> Result<(u64, u64), RadosError> {
+    let mut parts = object_name.splitn(2, '.');
+    match (parts.next(), parts.next()) {
+        (Some(ino), Some(object_no)) => {
+            let ino = try!(u64::from_str_radix(ino, 16).map_err(|e| {
+                RadosError::new(format!("Invalid inode in object name {}: {}", object_name, e))
+            }));
+            let object_no = try!(u64::from_str_radix(object_no, 16).map_err(|e| {
+                RadosError::new(format!("Invalid object number in object name {}: {}",
+                                        object_name,
+                                        e))
+            }));
+            Ok((ino, object_no))
+        }
+        _ => Err(RadosError::new(format!("{} is not a data object name", object_name))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INO: u64 = 0x10000000000;
+
+    fn object(object_no: u64, offset: u64, length: u64) -> ObjectExtent {
+        ObjectExtent {
+            object_name: format!("{:x}.{:08x}", INO, object_no),
+            object_no: object_no,
+            offset: offset,
+            length: length,
+        }
+    }
+
+    fn file(offset: u64, length: u64) -> FileExtent {
+        FileExtent {
+            offset: offset,
+            length: length,
+        }
+    }
+
+    // 4 byte stripe units, 2 of them per object, so each object set of
+    // three objects covers 24 bytes of the file
+    fn striped() -> ObjectMapper {
+        ObjectMapper::new(INO, 4, 3, 8).unwrap()
+    }
+
+    fn unstriped() -> ObjectMapper {
+        ObjectMapper::new(INO, 4, 1, 8).unwrap()
+    }
+
+    #[test]
+    fn file_to_objects_unstriped() {
+        let mapper = unstriped();
+        let cases = vec![
+            (0, 0, vec![]),
+            (0, 8, vec![object(0, 0, 8)]),
+            (2, 12, vec![object(0, 2, 6), object(1, 0, 6)]),
+            (6, 4, vec![object(0, 6, 2), object(1, 0, 2)]),
+            (17, 2, vec![object(2, 1, 2)]),
+        ];
+        for (offset, length, expected) in cases {
+            assert_eq!(mapper.file_to_objects(offset, length).unwrap(),
+                       expected,
+                       "{}~{}",
+                       offset,
+                       length);
+        }
+    }
+
+    #[test]
+    fn file_to_objects_striped() {
+        let mapper = striped();
+        let cases = vec![
+            // Within one stripe unit
+            (1, 2, vec![object(0, 1, 2)]),
+            // Across a stripe unit into the next object
+            (2, 4, vec![object(0, 2, 2), object(1, 0, 2)]),
+            // Across a stripe back to the first object
+            (10, 4, vec![object(2, 2, 2), object(0, 4, 2)]),
+            // Across an object set
+            (22, 4, vec![object(2, 6, 2), object(3, 0, 2)]),
+            (0,
+             24,
+             vec![object(0, 0, 4),
+                  object(1, 0, 4),
+                  object(2, 0, 4),
+                  object(0, 4, 4),
+                  object(1, 4, 4),
+                  object(2, 4, 4)]),
+        ];
+        for (offset, length, expected) in cases {
+            assert_eq!(mapper.file_to_objects(offset, length).unwrap(),
+                       expected,
+                       "{}~{}",
+                       offset,
+                       length);
+        }
+    }
+
+    #[test]
+    fn object_to_file_table() {
+        let cases = vec![
+            (unstriped(), 0, 0, 8, vec![file(0, 8)]),
+            (unstriped(), 1, 3, 4, vec![file(11, 4)]),
+            (striped(), 0, 0, 8, vec![file(0, 4), file(12, 4)]),
+            (striped(), 2, 3, 2, vec![file(11, 1), file(20, 1)]),
+            (striped(), 4, 2, 4, vec![file(30, 2), file(40, 2)]),
+        ];
+        for (mapper, object_no, offset, length, expected) in cases {
+            assert_eq!(mapper.object_to_file(object_no, offset, length).unwrap(),
+                       expected,
+                       "{:?} {} {}~{}",
+                       mapper,
+                       object_no,
+                       offset,
+                       length);
+        }
+    }
+
+    #[test]
+    fn file_to_objects_past_end() {
+        let max = u64::max_value();
+        assert!(striped().file_to_objects(max, 1).is_err());
+        assert!(striped().file_to_objects(1, max).is_err());
+        assert_eq!(striped().file_to_objects(max - 1, 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn object_to_file_past_end() {
+        assert!(striped().object_to_file(0, 6, 4).is_err());
+        assert!(striped().object_to_file(0, 1, u64::max_value()).is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        for mapper in vec![unstriped(), striped(), ObjectMapper::new(INO, 2, 4, 6).unwrap()] {
+            for offset in 0..60 {
+                for length in 1..60 {
+                    let mut next = offset;
+                    for extent in mapper.file_to_objects(offset, length).unwrap() {
+                        assert_eq!(extent.object_name, mapper.object_name(extent.object_no));
+                        let files = mapper.object_name_to_file(&extent.object_name,
+                                                               extent.offset,
+                                                               extent.length)
+                            .unwrap();
+                        for f in files {
+                            assert_eq!(f.offset, next, "{:?} {}~{}", mapper, offset, length);
+                            next += f.length;
+                        }
+                    }
+                    assert_eq!(next, offset + length, "{:?} {}~{}", mapper, offset, length);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn object_names() {
+        let mapper = ObjectMapper::new(INO, 4194304, 1, 4194304).unwrap();
+        assert_eq!(mapper.object_name(0), "10000000000.00000000");
+        assert_eq!(mapper.object_name(42), "10000000000.0000002a");
+        assert_eq!(mapper.file_to_objects(4194304 * 3 + 1, 1).unwrap()[0].object_name,
+                   "10000000000.00000003");
+        assert_eq!(parse_object_name("10000000000.0000002a").unwrap(), (INO, 42));
+        assert_eq!(parse_object_name(&mapper.object_name(7)).unwrap(), (INO, 7));
+        assert!(parse_object_name("10000000000").is_err());
+        assert!(parse_object_name("xyz.00000000").is_err());
+        assert!(mapper.object_name_to_file("10000000001.00000000", 0, 1).is_err());
+    }
+
+    #[test]
+    fn invalid_layouts() {
+        assert!(ObjectMapper::new(INO, 0, 1, 8).is_err());
+        assert!(ObjectMapper::new(INO, 4, 0, 8).is_err());
+        assert!(ObjectMapper::new(INO, 3, 1, 8).is_err());
+    }
+}